@@ -1,17 +1,34 @@
-use crate::egui_tools::EguiRenderer;
-use crate::wasm::Wasm;
-use crate::winit_wasi::{MyWindowWrapper, WinitEventToSurfaceProxy};
+use crate::chrome::{ChromeAction, ChromeRequest, PageChrome};
+use crate::console::Console;
+use crate::egui_tools::{draw_with_wgpu, EguiRenderer};
+use crate::frames::FrameClock;
+use crate::input::InputQueue;
+use crate::navigation::{HistoryAction, NavigationEvent, Navigator, PageContents, Source};
+use crate::network::Network;
+use crate::limits::PageLimits;
+use crate::permissions::{origin_of, Capability, PermissionRequest, PermissionStore};
+use crate::page::{BackgroundPolicy, WasmPage};
+use crate::software::SoftwarePainter;
+use crate::storage::{OriginUsage, Storage, DEFAULT_QUOTA};
+use crate::wasm::{ExitStatus, Runtime, Wasm};
+use crate::winit_wasi::{
+    EventSpace, MainThreadAction, MyWindowWrapper, WasiWinitEventLoopProxy,
+    WinitEventToSurfaceProxy,
+};
 use egui_wgpu::wgpu::SurfaceError;
 use egui_wgpu::{wgpu, ScreenDescriptor};
-use std::sync::{Arc, Mutex};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
 use std::mem::{drop};
 use std::sync::mpsc;
 use winit::application::ApplicationHandler;
-use winit::dpi::{LogicalPosition, LogicalSize, PhysicalSize, Position};
-use winit::event::{ElementState, KeyEvent, WindowEvent};
+use winit::dpi::{LogicalPosition, LogicalSize, PhysicalPosition, PhysicalSize, Position};
+use winit::event::{DeviceEvent, DeviceId, ElementState, KeyEvent, WindowEvent};
 use winit::keyboard::{KeyCode, PhysicalKey};
 use winit::event_loop::ActiveEventLoop;
-use winit::window::{Window, WindowId};
+use winit::window::{Fullscreen, Window, WindowId};
 use winit::raw_window_handle::HasRawWindowHandle;
 
 use egui_commonmark::*;
@@ -20,7 +37,6 @@ use pulldown_cmark::{Parser, Options};
 #[path = "fill.rs"]
 mod fill;
 
-#[derive(Clone)]
 struct Tab {
     // Example stuff:
     label: String,
@@ -30,29 +46,56 @@ struct Tab {
 
     // currently loaded page of tab
     contents: String,
+    // cached component the tab navigated to, if the page is wasm
+    wasm_path: Option<PathBuf>,
+    // output of the tab's wasm pages
+    console: Console,
+    network: Network,
+    // the wasm page running in the tab, kept while the tab is in the background
+    page: Option<WasmPage>,
+    // a page without graphics finished, the tab keeps showing what it printed
+    console_page: bool,
 
     // for history
     back: Vec<String>,
     forward: Vec<String>,
 }
 
+/// A page waiting for the user to decide on capabilities it has not been granted or denied yet.
+struct PermissionPrompt {
+    request: PermissionRequest,
+    choices: BTreeMap<Capability, bool>,
+}
+
 pub struct AppState {
-    pub device: wgpu::Device,
-    pub queue: wgpu::Queue,
-    pub surface_config: wgpu::SurfaceConfiguration,
-    pub surface: wgpu::Surface<'static>,
     pub scale_factor: f32,
     pub egui_renderer: EguiRenderer,
+    painter: Painter,
+}
+
+/// How the browser's own UI gets to the main window.
+enum Painter {
+    Gpu {
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        surface_config: wgpu::SurfaceConfiguration,
+        surface: wgpu::Surface<'static>,
+        renderer: egui_wgpu::Renderer,
+    },
+    /// wgpu found no adapter, egui is rasterized on the CPU instead.
+    Software(SoftwarePainter),
 }
 
 impl AppState {
+    /// Set up drawing the browser to `surface` with wgpu. Fails if wgpu has no adapter for it,
+    /// see `AppState::software`.
     async fn new(
         instance: &wgpu::Instance,
         surface: wgpu::Surface<'static>,
         window: &Window,
         width: u32,
         height: u32,
-    ) -> Self {
+    ) -> Result<Self, String> {
         let power_pref = wgpu::PowerPreference::default();
         let adapter = instance
             .request_adapter(&wgpu::RequestAdapterOptions {
@@ -61,7 +104,7 @@ impl AppState {
                 compatible_surface: Some(&surface),
             })
             .await
-            .expect("Failed to find an appropriate adapter");
+            .ok_or("no graphics adapter")?;
 
         let features = wgpu::Features::empty();
         let (device, queue) = adapter
@@ -75,7 +118,7 @@ impl AppState {
                 None,
             )
             .await
-            .expect("Failed to create device");
+            .map_err(|e| format!("failed to create a graphics device: {}", e))?;
 
         let swapchain_capabilities = surface.get_capabilities(&adapter);
         let selected_format = wgpu::TextureFormat::Bgra8UnormSrgb;
@@ -98,24 +141,104 @@ impl AppState {
 
         surface.configure(&device, &surface_config);
 
-        let egui_renderer = EguiRenderer::new(&device, surface_config.format, None, 1, window);
+        let renderer = egui_wgpu::Renderer::new(&device, surface_config.format, None, 1, true);
+        let egui_renderer = EguiRenderer::new(window);
 
         let scale_factor = 1.0;
 
-        Self {
-            device,
-            queue,
-            surface,
-            surface_config,
+        Ok(Self {
             egui_renderer,
             scale_factor,
-        }
+            painter: Painter::Gpu {
+                device,
+                queue,
+                surface_config,
+                surface,
+                renderer,
+            },
+        })
+    }
+
+    /// Draw the browser on the CPU and present it with softbuffer, for machines where wgpu
+    /// has no adapter at all.
+    fn software(window: Arc<Window>) -> Result<Self, String> {
+        let egui_renderer = EguiRenderer::new(&window);
+        Ok(Self {
+            egui_renderer,
+            scale_factor: 1.0,
+            painter: Painter::Software(SoftwarePainter::new(window)?),
+        })
     }
 
     fn resize_surface(&mut self, width: u32, height: u32) {
-        self.surface_config.width = width;
-        self.surface_config.height = height;
-        self.surface.configure(&self.device, &self.surface_config);
+        // the software painter follows the window on its own
+        if let Painter::Gpu {
+            device,
+            surface_config,
+            surface,
+            ..
+        } = &mut self.painter
+        {
+            surface_config.width = width;
+            surface_config.height = height;
+            surface.configure(device, surface_config);
+        }
+    }
+
+    /// Finish egui's frame and show it in `window`.
+    fn draw(&mut self, window: &Window, pixels_per_point: f32) {
+        let (tris, textures) = self.egui_renderer.end_frame(window, pixels_per_point);
+        match &mut self.painter {
+            Painter::Gpu {
+                device,
+                queue,
+                surface_config,
+                surface,
+                renderer,
+            } => {
+                let screen_descriptor = ScreenDescriptor {
+                    size_in_pixels: [surface_config.width, surface_config.height],
+                    pixels_per_point,
+                };
+                for (id, image_delta) in &textures.set {
+                    renderer.update_texture(device, queue, *id, image_delta);
+                }
+                match surface.get_current_texture() {
+                    Ok(surface_texture) => {
+                        let surface_view = surface_texture
+                            .texture
+                            .create_view(&wgpu::TextureViewDescriptor::default());
+                        let mut encoder = device
+                            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+                        draw_with_wgpu(
+                            renderer,
+                            device,
+                            queue,
+                            &mut encoder,
+                            &surface_view,
+                            &tris,
+                            &screen_descriptor,
+                        );
+                        queue.submit(Some(encoder.finish()));
+                        // with vsync this waits for the display, which paces the page's frames as well
+                        surface_texture.present();
+                    }
+                    // outdated while resizing or minimized, the next frame gets a new one
+                    Err(SurfaceError::Outdated) => log::debug!("wgpu surface outdated"),
+                    Err(e) => log::error!("Failed to acquire next swap chain texture: {}", e),
+                }
+                for id in &textures.free {
+                    renderer.free_texture(id);
+                }
+            }
+            Painter::Software(painter) => {
+                let size = window.inner_size();
+                let size = [size.width, size.height];
+                if let Err(e) = painter.paint(size, &tris, &textures, pixels_per_point) {
+                    log::error!("Failed to paint the browser: {}", e);
+                }
+            }
+        }
     }
 }
 
@@ -123,62 +246,109 @@ pub struct App {
     instance: wgpu::Instance,
     state: Option<AppState>,
     window: Option<Arc<Window>>,
-    child_window: Option<Arc<Window>>,
     // wasi_surface: Option<wasi_surface_wasmtime::Surface>,
-    wasi_event_handler: Option<WinitEventToSurfaceProxy>,
     parent_window_id: WindowId,
-    child_window_id: WindowId,
     current_status: String,
     current_location: String,
     current_tab: i32,
     current_page: String,
+    current_wasm: Option<PathBuf>,
     tabs: Vec<Tab>,
     tab_counter: i32,
-    wasm_runtime: Arc<Mutex<Wasm>>,
+    navigator: Navigator,
+    background_policy: BackgroundPolicy,
+    // engine, linker and caches shared by every page, `None` if it failed to start
+    runtime: Option<Runtime>,
+    // hands guests' main-thread work to our event loop, see `user_event`
+    main_thread: WasiWinitEventLoopProxy,
+    permissions: PermissionStore,
+    permission_sender: mpsc::Sender<PermissionRequest>,
+    permission_receiver: mpsc::Receiver<PermissionRequest>,
+    permission_prompt: Option<PermissionPrompt>,
+    // what pages ask of the chrome through `m:browser/chrome`
+    chrome_sender: mpsc::Sender<ChromeRequest>,
+    chrome_receiver: mpsc::Receiver<ChromeRequest>,
+    show_console: bool,
+    show_network: bool,
+    storage: Storage,
+    // what the storage panel shows, `None` while it is closed
+    storage_panel: Option<Vec<OriginUsage>>,
+    // central panel in physical pixels relative to the main window
+    page_area: Option<(PhysicalPosition<i32>, PhysicalSize<u32>)>,
     quit_pressed: bool,
     spawn_child_window: bool,
     close_child_window: bool,
-    sender: Option<mpsc::Sender<()>>,
-    event_sender: Option<mpsc::Sender<()>>,
-    event_receiver: Option<Arc<Mutex<mpsc::Receiver<()>>>>,
+    // covered by other windows, as far as the platform can tell
+    window_occluded: bool,
+    // the main window is fullscreen because the current page asked for it
+    page_fullscreen: bool,
 
 }
 
 impl App {
-    pub fn new() -> Self {
+    pub fn new(main_thread: WasiWinitEventLoopProxy) -> Self {
         let instance = egui_wgpu::wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
-        let (tx, rx) = mpsc::channel();
-        let (event_tx, event_rx) = mpsc::channel();
+        let (permission_tx, permission_rx) = mpsc::channel();
+        let (chrome_tx, chrome_rx) = mpsc::channel();
+        let mut navigator = Navigator::new();
+        navigator.start(
+            0,
+            "https://raw.githubusercontent.com/abemassry/m-browser/refs/heads/main/README.md".to_string(),
+            HistoryAction::Reload,
+        );
+        let runtime = match Runtime::new(PageLimits::default()) {
+            Ok(runtime) => Some(runtime),
+            Err(e) => {
+                log::error!("Error creating wasm runtime: {e:#}");
+                None
+            }
+        };
         Self {
             instance,
             state: None,
             window: None,
-            child_window: None,
             // wasi_surface: None,
-            wasi_event_handler: None,
             parent_window_id: 1.into(),
-            child_window_id: 2.into(),
             current_status: "Loading...".to_string(),
             current_location: "https://raw.githubusercontent.com/abemassry/m-browser/refs/heads/main/README.md".to_string(),
             current_tab: 0,
             current_page: "".to_string(),
+            current_wasm: None,
             tabs: vec![Tab {
                 label: "".to_string(),
                 location: "https://raw.githubusercontent.com/abemassry/m-browser/refs/heads/main/README.md".to_string(),
                 status: "Loaded".to_string(),
                 contents: "".to_string(),
+                wasm_path: None,
+                console: Console::new(),
+                network: Network::new(),
+                page: None,
+                console_page: false,
                 back: Vec::new(),
                 forward: Vec::new(),
                 identifier: 0,
             }],
             tab_counter: 0,
-            wasm_runtime: Arc::new(Mutex::new(Wasm::new(rx).unwrap())),
+            navigator,
+            background_policy: BackgroundPolicy::default(),
+            runtime,
+            main_thread,
+            permissions: PermissionStore::open_default(),
+            permission_sender: permission_tx,
+            permission_receiver: permission_rx,
+            permission_prompt: None,
+            chrome_sender: chrome_tx,
+            chrome_receiver: chrome_rx,
+            show_console: false,
+            show_network: false,
+            storage: Storage::new(DEFAULT_QUOTA),
+            storage_panel: None,
+            page_area: None,
             quit_pressed: false,
             spawn_child_window: false,
             close_child_window: false,
-            sender: Some(tx),
-            event_sender: Some(event_tx),
-            event_receiver: Some(Arc::new(Mutex::new(event_rx))),
+            window_occluded: false,
+            page_fullscreen: false,
         }
     }
 
@@ -191,75 +361,362 @@ impl App {
 
         let _ = window.request_inner_size(PhysicalSize::new(initial_width, initial_height));
 
-        let surface = self
-            .instance
-            .create_surface(window.clone())
-            .expect("Failed to create surface!");
-
-        let state = AppState::new(
-            &self.instance,
-            surface,
-            &window,
-            initial_width,
-            initial_width,
-        )
-        .await;
-
+        let state = match self.instance.create_surface(window.clone()) {
+            Ok(surface) => {
+                AppState::new(&self.instance, surface, &window, initial_width, initial_width).await
+            }
+            Err(e) => Err(format!("failed to create a wgpu surface: {}", e)),
+        };
+        // without a graphics adapter the browser draws on the CPU
+        let state = state.or_else(|e| {
+            log::warn!("Cannot draw with wgpu, falling back to software rendering: {}", e);
+            AppState::software(window.clone())
+        });
+
+        self.parent_window_id = window.id();
+        match state {
+            Ok(state) => {
+                self.state.get_or_insert(state);
+            }
+            Err(e) => {
+                // nothing inside the window can be drawn, its title is all that shows
+                log::error!("Cannot draw the browser: {}", e);
+                self.current_status = format!("Cannot draw the browser: {}", e);
+                window.set_title(&format!("M - {}", self.current_status));
+            }
+        }
         self.window.get_or_insert(window);
-        self.state.get_or_insert(state);
     }
 
     fn handle_resized(&mut self, width: u32, height: u32) {
         if width > 0 && height > 0 {
-            self.state.as_mut().unwrap().resize_surface(width, height);
+            if let Some(state) = self.state.as_mut() {
+                state.resize_surface(width, height);
+            }
         }
     }
 
-    fn handle_redraw(&mut self) {
-        // Attempt to handle minimizing window
-        if let Some(window) = self.window.as_ref() {
-            if let Some(min) = window.is_minimized() {
-                if min {
-                    println!("Window is minimized");
-                    return;
+    /// Terminate the running guest and hide its window.
+    fn stop_child_window(&mut self) {
+        let Some(tab) = self.current_tab_mut() else {
+            return;
+        };
+        let Some(page) = tab.page.take() else {
+            return;
+        };
+        let status = page.stop();
+        tab.console.note(format!("Page {}", status));
+        let identifier = tab.identifier;
+        // the page was waiting on the prompt, nobody is anymore
+        if self
+            .permission_prompt
+            .as_ref()
+            .is_some_and(|prompt| prompt.request.tab == identifier)
+        {
+            self.permission_prompt = None;
+        }
+    }
+
+    fn current_tab_mut(&mut self) -> Option<&mut Tab> {
+        let current_tab = self.current_tab;
+        self.tabs.iter_mut().find(|t| t.identifier == current_tab)
+    }
+
+    fn current_wasm_page(&mut self) -> Option<&mut WasmPage> {
+        self.current_tab_mut().and_then(|tab| tab.page.as_mut())
+    }
+
+    /// Show the tab `identifier`, putting the current tab's page in the background.
+    ///
+    /// A tab whose page is still running gets it back as it was left. A wasm tab whose page was
+    /// stopped starts it again from the cached component, one whose page without graphics finished
+    /// shows that page's console, and a markdown tab shows what it loaded last. Only a tab that has not loaded anything yet is fetched.
+    fn switch_tab(&mut self, identifier: i32) {
+        if identifier != self.current_tab {
+            let policy = self.background_policy;
+            if let Some(tab) = self.current_tab_mut() {
+                let keep = tab.page.as_mut().is_some_and(|page| page.hide(policy));
+                if !keep {
+                    if let Some(page) = tab.page.take() {
+                        tab.console.note(format!("Background page {}", page.stop()));
+                    }
                 }
             }
         }
 
-        let state = self.state.as_mut().unwrap();
+        self.current_tab = identifier;
+        let Some(tab) = self.tabs.iter_mut().find(|t| t.identifier == identifier) else {
+            return;
+        };
+        self.current_location = tab.location.clone();
+        self.current_page = tab.contents.clone();
+        self.current_wasm = tab.wasm_path.clone();
+        self.current_status = tab.status.clone();
+        if let Some(window) = self.window.as_ref() {
+            window.set_title(format!("M - {}", tab.label).as_str());
+        }
+        if let Some(page) = tab.page.as_mut() {
+            page.show();
+        } else if tab.console_page {
+            // the page finished, its output is all there is to show
+        } else if self.current_wasm.is_some() {
+            self.spawn_child_window = true;
+        } else if tab.contents.is_empty() {
+            // a tab that was shown before keeps its page, only a new one has to load
+            self.navigator.start(identifier, tab.location.clone(), HistoryAction::Reload);
+        }
+    }
 
-        let screen_descriptor = ScreenDescriptor {
-            size_in_pixels: [state.surface_config.width, state.surface_config.height],
-            pixels_per_point: self.window.as_ref().unwrap().scale_factor() as f32
-                * state.scale_factor,
+    /// Replace tab `identifier`'s contents with a rendered error page.
+    fn show_error_page(&mut self, identifier: i32, title: &str, details: &str) {
+        let Some(tab) = self.tabs.iter_mut().find(|t| t.identifier == identifier) else {
+            return;
         };
+        let page = format!("# {}\n\n`{}`\n\n{}\n", title, tab.location, details);
+        tab.contents = page.clone();
+        tab.wasm_path = None;
+        tab.console_page = false;
+        if identifier == self.current_tab {
+            self.current_page = page;
+            self.current_wasm = None;
+        }
+    }
 
-        let surface_texture = state.surface.get_current_texture();
+    /// Apply whatever the navigation workers have reported since the last frame.
+    fn handle_navigation_events(&mut self) {
+        for event in self.navigator.poll() {
+            match event {
+                NavigationEvent::Loading(request) => {
+                    if let Some(tab) = self.tabs.iter_mut().find(|t| t.identifier == request.tab) {
+                        tab.status = "Loading...".to_string();
+                    }
+                    if request.tab == self.current_tab {
+                        self.current_status = "Loading...".to_string();
+                    }
+                }
+                NavigationEvent::Loaded(request, contents, classification, source) => {
+                    let Some(tab) = self.tabs.iter_mut().find(|t| t.identifier == request.tab) else {
+                        continue;
+                    };
+                    match request.action {
+                        HistoryAction::Push => {
+                            if tab.location != request.location {
+                                tab.back.push(tab.location.clone());
+                            }
+                            tab.forward.clear(); // clear forward history
+                        }
+                        HistoryAction::Back => {
+                            tab.back.pop();
+                            tab.forward.push(tab.location.clone());
+                        }
+                        HistoryAction::Forward => {
+                            tab.forward.pop();
+                            tab.back.push(tab.location.clone());
+                        }
+                        HistoryAction::Reload => {}
+                    }
+                    tab.location = request.location;
+                    // the new page replaces whatever ran in the tab before
+                    if let Some(page) = tab.page.take() {
+                        tab.console.note(format!("Page {}", page.stop()));
+                    }
+                    tab.console_page = false;
+                    match contents {
+                        PageContents::Markdown(text) => {
+                            tab.contents = text;
+                            tab.wasm_path = None;
+                        }
+                        PageContents::Wasm(path) => {
+                            tab.contents = path.display().to_string();
+                            tab.wasm_path = Some(path);
+                        }
+                    }
+                    tab.label = get_heading(tab.location.clone(), tab.contents.clone());
+                    tab.status = match source {
+                        Source::Network => format!("Loaded ({})", classification),
+                        Source::OfflineCopy(reason) => {
+                            tab.console.note(format!("Offline copy, the server is unreachable: {}", reason));
+                            format!("Offline copy ({}), the server is unreachable", classification)
+                        }
+                    };
 
-        match surface_texture {
-            Err(SurfaceError::Outdated) => {
-                // Ignoring outdated to allow resizing and minimization
-                println!("wgpu surface outdated");
-                return;
+                    if tab.identifier != self.current_tab {
+                        continue;
+                    }
+                    self.current_location = tab.location.clone();
+                    self.current_page = tab.contents.clone();
+                    self.current_wasm = tab.wasm_path.clone();
+                    if self.current_wasm.is_some() {
+                        self.spawn_child_window = true;
+                    }
+                    if let Some(window) = self.window.as_ref() {
+                        let title = format!("M - {}", tab.label);
+                        window.set_title(title.as_str());
+                    }
+                    self.current_status = tab.status.clone();
+                }
+                NavigationEvent::Failed(request, e) => {
+                    log::warn!("Failed to load {}: {}", request.location, e);
+                    let status = format!("Failed to load page: {}", e);
+                    if let Some(tab) = self.tabs.iter_mut().find(|t| t.identifier == request.tab) {
+                        tab.status = status.clone();
+                    }
+                    if request.tab == self.current_tab {
+                        self.current_status = status;
+                    }
+                }
             }
-            Err(_) => {
-                surface_texture.expect("Failed to acquire next swap chain texture");
-                return;
+        }
+    }
+
+    /// Answer permission requests from running pages, prompting for anything undecided.
+    fn handle_permission_requests(&mut self) {
+        if self.permission_prompt.is_some() {
+            return;
+        }
+        while let Ok(request) = self.permission_receiver.try_recv() {
+            let undecided = self.permissions.undecided(&request.origin, &request.capabilities);
+            if undecided.is_empty() {
+                let grants = self.permissions.grants(&request.origin, &request.capabilities);
+                let _ = request.reply.send(grants);
+                continue;
             }
-            Ok(_) => {}
+            self.permission_prompt = Some(PermissionPrompt {
+                request,
+                choices: undecided.into_iter().map(|c| (c, false)).collect(),
+            });
+            break;
+        }
+    }
+
+    fn answer_permission_prompt(&mut self, allow: bool) {
+        let Some(prompt) = self.permission_prompt.take() else {
+            return;
         };
+        let origin = &prompt.request.origin;
+        for (capability, chosen) in prompt.choices {
+            self.permissions.remember(origin, capability, allow && chosen);
+        }
+        let grants = self.permissions.grants(origin, &prompt.request.capabilities);
+        let _ = prompt.request.reply.send(grants);
+    }
 
-        let surface_texture = surface_texture.unwrap();
+    /// Apply what pages asked of the chrome since the last frame.
+    ///
+    /// Titles and statuses are kept per tab. Navigation goes through the navigator like a
+    /// clicked link, and only the page in the shown tab may open new tabs.
+    fn handle_chrome_requests(&mut self) {
+        while let Ok(request) = self.chrome_receiver.try_recv() {
+            let current = request.tab == self.current_tab;
+            match request.action {
+                ChromeAction::Navigate(location) => {
+                    if current {
+                        self.current_location = location.clone();
+                    }
+                    self.navigator.start(request.tab, location, HistoryAction::Push);
+                }
+                ChromeAction::OpenTab(location) => {
+                    if current {
+                        self.open_tab(location);
+                    } else if let Some(tab) = self.tabs.iter_mut().find(|t| t.identifier == request.tab) {
+                        tab.console.note(format!("Blocked opening {} from a background tab", location));
+                    }
+                }
+                ChromeAction::SetTitle(title) => {
+                    let Some(tab) = self.tabs.iter_mut().find(|t| t.identifier == request.tab) else {
+                        continue;
+                    };
+                    tab.label = title;
+                    if current {
+                        if let Some(window) = self.window.as_ref() {
+                            window.set_title(format!("M - {}", tab.label).as_str());
+                        }
+                    }
+                }
+                ChromeAction::SetStatus(status) => {
+                    let Some(tab) = self.tabs.iter_mut().find(|t| t.identifier == request.tab) else {
+                        continue;
+                    };
+                    tab.status = status;
+                    if current {
+                        self.current_status = tab.status.clone();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Add a tab loading `location` and show it.
+    fn open_tab(&mut self, location: String) {
+        self.tab_counter += 1;
+        let identifier = self.tab_counter;
+        self.tabs.push(Tab {
+            label: "New Tab".to_owned(),
+            location,
+            status: "Loading...".to_owned(),
+            contents: "".to_owned(),
+            wasm_path: None,
+            console: Console::new(),
+            network: Network::new(),
+            page: None,
+            console_page: false,
+            back: Vec::new(),
+            forward: Vec::new(),
+            identifier,
+        });
+        // a tab with nothing loaded yet starts loading its location when shown
+        self.switch_tab(identifier);
+    }
+
+    fn handle_redraw(&mut self) {
+        self.handle_navigation_events();
+        self.handle_permission_requests();
+        self.handle_chrome_requests();
+        for tab in &mut self.tabs {
+            tab.console.poll();
+        }
+
+        // Attempt to handle minimizing window
+        if let Some(window) = self.window.as_ref() {
+            if let Some(min) = window.is_minimized() {
+                if min {
+                    println!("Window is minimized");
+                    return;
+                }
+            }
+        }
 
-        let surface_view = surface_texture
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
+        // the page takes up the whole screen, without the toolbar and panels
+        let page_fullscreen = self.current_wasm_page().is_some_and(|page| page.fullscreen());
+        if page_fullscreen != self.page_fullscreen {
+            self.page_fullscreen = page_fullscreen;
+            if let Some(window) = self.window.as_ref() {
+                window.set_fullscreen(page_fullscreen.then_some(Fullscreen::Borderless(None)));
+            }
+        }
 
-        let mut encoder = state
-            .device
-            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        let showing_wasm = self.current_wasm_page().is_some();
+        let showing_console_page = self.current_tab_mut().is_some_and(|tab| {
+            tab.console_page || tab.page.as_ref().is_some_and(|page| page.headless())
+        });
+        let Some(state) = self.state.as_mut() else {
+            return;
+        };
 
         let window = self.window.as_ref().unwrap();
+        let pixels_per_point = window.scale_factor() as f32 * state.scale_factor;
+
+        // permission prompt decision, applied once the frame is drawn
+        let mut answer = None;
+        // where the central panel ended up this frame, in points
+        let mut page_rect = None;
+        // egui windows and popups drawn this frame, which the page's window would hide
+        let mut overlays = Vec::new();
+        // tab clicked in the side panel, switched to once the frame is drawn
+        let mut switch_to = None;
+        // storage panel actions, applied once the frame is drawn
+        let mut clear_origin: Option<String> = None;
+        let mut refresh_storage = false;
 
         {
             state.egui_renderer.begin_frame(window);
@@ -292,7 +749,7 @@ impl App {
             //
             //    egui browser window
             egui::TopBottomPanel::top("top_panel")
-                .show(state.egui_renderer.context(), |ui| {
+                .show_animated(state.egui_renderer.context(), !page_fullscreen, |ui| {
                     egui::menu::bar(ui, |ui| {
                         ui.menu_button(egui_material_icons::icons::ICON_MENU, |ui| {
                             egui::widgets::global_theme_preference_buttons(ui);
@@ -306,39 +763,29 @@ impl App {
                             }
                         });
                         ui.add_space(3.0);
+                        ui.toggle_value(&mut self.show_console, egui_material_icons::icons::ICON_TERMINAL)
+                            .on_hover_text("Console");
+                        ui.toggle_value(&mut self.show_network, egui_material_icons::icons::ICON_LAN)
+                            .on_hover_text("Network");
+                        let mut show_storage = self.storage_panel.is_some();
+                        if ui
+                            .toggle_value(&mut show_storage, egui_material_icons::icons::ICON_STORAGE)
+                            .on_hover_text("Site data")
+                            .changed()
+                        {
+                            self.storage_panel = show_storage.then(|| self.storage.usage());
+                        }
                         ui.add_space(1.0);
                         ui.button(egui_material_icons::icons::ICON_ARROW_BACK)
                             .on_hover_text("Back")
                             .clicked().then(|| {
                                 if let Err(e) = back() {
                                     self.current_status = e.to_string();
-                                } else {
-                                    for tab in &mut self.tabs {
-                                        if tab.identifier == self.current_tab {
-                                            if tab.back.is_empty() {
-                                                return;
-                                            }
-                                            println!("inside if statement for back button of tab");
-                                            tab.forward.push(tab.location.clone());
-                                            tab.location = tab.back.pop().unwrap_or_else(|| tab.location.clone());
-                                            self.current_location = tab.location.clone();
-                                            self.current_page = go(tab.location.clone());
-                                            if is_wasm(self.current_location.clone()) {
-                                                println!("opening child window, back button");
-                                                self.spawn_child_window = true;
-                                            } else {
-                                                println!("Closing child window if open, back button");
-                                                self.close_child_window = true;
-                                            }
-                                            tab.contents = self.current_page.clone();
-                                            tab.label = get_heading(tab.location.clone(), tab.contents.clone());
-                                            if let Some(window) = self.window.as_ref() {
-                                                let title = format!("M - {}", tab.label);
-                                                window.set_title(title.as_str());
-                                            }
-                                        }
+                                } else if let Some(tab) = self.tabs.iter().find(|t| t.identifier == self.current_tab) {
+                                    if let Some(location) = tab.back.last() {
+                                        self.current_location = location.clone();
+                                        self.navigator.start(self.current_tab, location.clone(), HistoryAction::Back);
                                     }
-                                    self.current_status = "Loaded".to_string();
                                 }
                             });
                         ui.add_space(1.0);
@@ -347,33 +794,11 @@ impl App {
                             .clicked().then(|| {
                                 if let Err(e) = forward() {
                                     self.current_status = e.to_string();
-                                } else {
-                                    for tab in &mut self.tabs {
-                                        if tab.identifier == self.current_tab {
-                                            if tab.forward.is_empty() {
-                                                return;
-                                            }
-                                            println!("inside if statement for forward button of tab");
-                                            tab.back.push(tab.location.clone());
-                                            tab.location = tab.forward.pop().unwrap_or_else(|| tab.location.clone());
-                                            self.current_location = tab.location.clone();
-                                            self.current_page = go(tab.location.clone());
-                                            if is_wasm(self.current_location.clone()) {
-                                                println!("opening child window, forward button");
-                                                self.spawn_child_window = true;
-                                            } else {
-                                                println!("Closing child window if open, forward button");
-                                                self.close_child_window = true;
-                                            }
-                                            tab.contents = self.current_page.clone();
-                                            tab.label = get_heading(tab.location.clone(), tab.contents.clone());
-                                            if let Some(window) = self.window.as_ref() {
-                                                let title = format!("M - {}", tab.label);
-                                                window.set_title(title.as_str());
-                                            }
-                                        }
+                                } else if let Some(tab) = self.tabs.iter().find(|t| t.identifier == self.current_tab) {
+                                    if let Some(location) = tab.forward.last() {
+                                        self.current_location = location.clone();
+                                        self.navigator.start(self.current_tab, location.clone(), HistoryAction::Forward);
                                     }
-                                    self.current_status = "Loaded".to_string();
                                 }
                             });
                         ui.add_space(1.0);
@@ -381,30 +806,7 @@ impl App {
                         let text_edit_width = ui.available_width() - button_width;
                         let response = ui.add_sized([text_edit_width.max(0.0), 20.0], egui::TextEdit::singleline(&mut self.current_location));
                         if response.lost_focus() && response.ctx.input(|i| i.key_pressed(egui::Key::Enter)) {
-                            self.current_status = "Loading...".to_string();
-                            self.current_page = go(self.current_location.clone());
-                            if is_wasm(self.current_location.clone()) {
-                                self.spawn_child_window = true;
-                            } else {
-                                println!("Closing child window if open, tab");
-                                self.close_child_window = true;
-                                for tab in &mut self.tabs {
-                                    if tab.identifier == self.current_tab {
-                                        tab.back.push(tab.location.clone());
-                                        tab.forward.clear(); // clear forward history
-                                        tab.location = self.current_location.clone();
-                                        tab.contents = self.current_page.clone();
-                                        tab.label = get_heading(tab.location.clone(), tab.contents.clone());
-                                        if let Some(window) = self.window.as_ref() {
-                                            let title = format!("M - {}", tab.label);
-                                            window.set_title(title.as_str());
-                                        }
-                                        break;
-                                    }
-                                }
-
-                                self.current_status = "Loaded".to_string();
-                            }
+                            self.navigator.start(self.current_tab, self.current_location.clone(), HistoryAction::Push);
                         }
                         ui.add_space(1.0);
 
@@ -412,67 +814,84 @@ impl App {
                             .on_hover_text("Go")
                             .clicked()
                             .then(|| {
-                                self.current_status = "Loading...".to_string();
-                                self.current_page = go(self.current_location.clone());
-                                if is_wasm(self.current_location.clone()) {
-                                    self.spawn_child_window = true;
-                                } else {
-                                    println!("Closing child window if open, tab");
-                                    self.close_child_window = true;
-                                    for tab in &mut self.tabs {
-                                        if tab.identifier == self.current_tab {
-                                            tab.back.push(tab.location.clone());
-                                            tab.forward.clear(); // clear forward history
-                                            tab.location = self.current_location.clone();
-                                            tab.contents = self.current_page.clone();
-                                            tab.label = get_heading(tab.location.clone(), tab.contents.clone());
-                                            if let Some(window) = self.window.as_ref() {
-                                                let title = format!("M - {}", tab.label);
-                                                window.set_title(title.as_str());
-                                            }
-                                            break;
-                                        }
-                                    }
-
-                                    self.current_status = "Loaded".to_string();
-                                }
+                                self.navigator.start(self.current_tab, self.current_location.clone(), HistoryAction::Push);
                             });
 
                     });
                 });
 
-                egui::TopBottomPanel::bottom("bottom_panel").show(state.egui_renderer.context(), |ui| {
+                egui::TopBottomPanel::bottom("bottom_panel").show_animated(state.egui_renderer.context(), !page_fullscreen, |ui| {
                     let mut status_display: String = "Status: ".to_owned();
                     let status: &str = self.current_status.as_str();
                     status_display.push_str(status);
                     ui.label(status_display);
                 });
 
-                egui::SidePanel::left("side_panel").show(state.egui_renderer.context(), |ui| {
-                    ui.separator();
-                    for tab in &mut self.tabs {
-                        if ui.button(&tab.label).clicked() {
-                            self.current_tab = tab.identifier;
-                            self.current_location = tab.location.clone();
-                            self.current_page = go(self.current_location.clone());
-                            if is_wasm(self.current_location.clone()) {
-                                self.spawn_child_window = true;
-                            } else {
-                                println!("Closing child window if open, tab");
-                                self.close_child_window = true;
+                if self.show_console && !showing_console_page && !page_fullscreen {
+                    egui::TopBottomPanel::bottom("console_panel")
+                        .resizable(true)
+                        .default_height(200.0)
+                        .show(state.egui_renderer.context(), |ui| {
+                            if let Some(tab) = self.tabs.iter_mut().find(|t| t.identifier == self.current_tab) {
+                                tab.console.ui(ui);
                             }
-                            println!("inside if statement for tab");
-                            tab.back.push(tab.location.clone());
-                            tab.forward.clear(); // clear forward history
-                            tab.location = self.current_location.clone();
-                            tab.contents = self.current_page.clone();
-                            tab.label = get_heading(tab.location.clone(), tab.contents.clone());
-                            if let Some(window) = self.window.as_ref() {
-                                let title = format!("M - {}", tab.label);
-                                window.set_title(title.as_str());
+                        });
+                }
+
+                if let Some(usage) = self.storage_panel.as_ref().filter(|_| !page_fullscreen) {
+                    egui::SidePanel::right("storage_panel")
+                        .resizable(true)
+                        .show(state.egui_renderer.context(), |ui| {
+                            ui.horizontal(|ui| {
+                                ui.heading("Site data");
+                                if ui.button("Refresh").clicked() {
+                                    refresh_storage = true;
+                                }
+                            });
+                            ui.separator();
+                            if usage.is_empty() {
+                                ui.label("No site has stored anything.");
                             }
+                            egui::ScrollArea::vertical().show(ui, |ui| {
+                                for origin in usage {
+                                    let quota = self.storage.quota();
+                                    egui::CollapsingHeader::new(&origin.origin)
+                                        .id_salt(&origin.origin)
+                                        .show(ui, |ui| {
+                                            ui.label(format!(
+                                                "Storage: {} of {} KiB",
+                                                origin.bytes.div_ceil(1024),
+                                                quota / 1024
+                                            ));
+                                            ui.label(format!("Files: {} KiB", origin.file_bytes.div_ceil(1024)));
+                                            for (key, size) in &origin.entries {
+                                                ui.monospace(format!("{} ({} bytes)", key, size));
+                                            }
+                                            if ui.button("Clear site data").clicked() {
+                                                clear_origin = Some(origin.origin.clone());
+                                            }
+                                        });
+                                }
+                            });
+                        });
+                }
 
-                            self.current_status = "Loaded".to_string();
+                if self.show_network && !page_fullscreen {
+                    egui::TopBottomPanel::bottom("network_panel")
+                        .resizable(true)
+                        .default_height(200.0)
+                        .show(state.egui_renderer.context(), |ui| {
+                            if let Some(tab) = self.tabs.iter_mut().find(|t| t.identifier == self.current_tab) {
+                                tab.network.ui(ui);
+                            }
+                        });
+                }
+
+                egui::SidePanel::left("side_panel").show_animated(state.egui_renderer.context(), !page_fullscreen, |ui| {
+                    ui.separator();
+                    for tab in &mut self.tabs {
+                        if ui.button(&tab.label).clicked() {
+                            switch_to = Some(tab.identifier);
                         }
                     }
 
@@ -483,22 +902,59 @@ impl App {
                             location: "https://raw.githubusercontent.com/abemassry/m-browser/refs/heads/main/README.md".to_owned(),
                             status: "Loaded".to_owned(),
                             contents: "".to_owned(),
+                            wasm_path: None,
+                            console: Console::new(),
+                            network: Network::new(),
+                            page: None,
+                            console_page: false,
                             back: Vec::new().to_owned(),
                             forward: Vec::new().to_owned(),
                             identifier: self.tab_counter,
                         };
-                        self.tabs.push(new_tab.clone());
-                        self.current_tab = self.tab_counter;
-                        self.current_location = new_tab.location.clone();
-                        self.current_page = new_tab.contents.clone();
+                        switch_to = Some(new_tab.identifier);
+                        self.tabs.push(new_tab);
 
                     }
                 });
 
-                if self.child_window_id != 2.into() {
-                    //println!("Child window is open");
+                if let Some(prompt) = self.permission_prompt.as_mut() {
+                    egui::Window::new("Permissions")
+                        .collapsible(false)
+                        .resizable(false)
+                        .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+                        .show(state.egui_renderer.context(), |ui| {
+                            ui.label(format!("{} wants to:", prompt.request.origin));
+                            for (capability, allowed) in prompt.choices.iter_mut() {
+                                ui.checkbox(allowed, capability.description());
+                            }
+                            ui.label("Your choice is remembered for this site.");
+                            ui.horizontal(|ui| {
+                                if ui.button("Allow selected").clicked() {
+                                    answer = Some(true);
+                                }
+                                if ui.button("Deny all").clicked() {
+                                    answer = Some(false);
+                                }
+                            });
+                        });
+                }
+
+                if showing_console_page {
+                    // a page without graphics is all console
+                    let panel = egui::CentralPanel::default().show(state.egui_renderer.context(), |ui| {
+                        if let Some(tab) = self.tabs.iter_mut().find(|t| t.identifier == self.current_tab) {
+                            tab.console.ui(ui);
+                        }
+                    });
+                    page_rect = Some(panel.response.rect);
+                } else if showing_wasm {
+                    // reserve the panel, the page's window is laid over it below
+                    let panel = egui::CentralPanel::default()
+                        .frame(egui::Frame::NONE)
+                        .show(state.egui_renderer.context(), |_ui| {});
+                    page_rect = Some(panel.response.rect);
                 } else {
-                    egui::CentralPanel::default().show(state.egui_renderer.context(), |ui| {
+                    let panel = egui::CentralPanel::default().show(state.egui_renderer.context(), |ui| {
 
                         let binding = self.current_page.clone();
                         let markdown = binding.as_str();
@@ -532,31 +988,7 @@ impl App {
                                 if cache.get_link_hook(&link) == Some(true) {
                                     println!("Link was clicked {link}");
                                     self.current_location = link.clone();
-                                    self.current_status = "Loading...".to_string();
-                                    self.current_page = go(self.current_location.clone());
-                                    if is_wasm(self.current_location.clone()) {
-                                        self.spawn_child_window = true;
-                                    } else {
-                                        println!("Closing child window if open, link");
-                                        self.close_child_window = true;
-                                        self.current_status = "Loaded".to_string();
-                                    }
-                                    for tab in &mut self.tabs {
-                                        if tab.identifier == self.current_tab {
-                                            println!("inside if statement for tab");
-                                            tab.back.push(tab.location.clone());
-                                            tab.forward.clear(); // clear forward history
-                                            tab.location = self.current_location.clone();
-                                            tab.contents = self.current_page.clone();
-                                            tab.label = get_heading(tab.location.clone(), tab.contents.clone());
-                                            if let Some(window) = self.window.as_ref() {
-                                                let title = format!("M - {}", tab.label);
-                                                window.set_title(title.as_str());
-                                            }
-                                        }
-
-                                        self.current_status = "Loaded".to_string();
-                                    }
+                                    self.navigator.start(self.current_tab, link.clone(), HistoryAction::Push);
                                 }
                                 //ui.hyperlink_to(link, link);
                             }
@@ -564,27 +996,108 @@ impl App {
 
                         });
                     });
+                    page_rect = Some(panel.response.rect);
                 }
                 // end of egui browser window
+                overlays = egui_overlays(state.egui_renderer.context());
+
+                // egui-winit owns the main window's IME, so the page's text cursor goes through it
+                let current_tab = self.current_tab;
+                let ime_area = self
+                    .tabs
+                    .iter()
+                    .find(|t| t.identifier == current_tab)
+                    .and_then(|tab| tab.page.as_ref())
+                    .and_then(|page| page.main_window_ime_area());
+                if let Some((position, size)) = ime_area {
+                    let rect = egui::Rect::from_min_size(
+                        egui::pos2(position.x as f32, position.y as f32) / pixels_per_point,
+                        egui::vec2(size.width as f32, size.height as f32) / pixels_per_point,
+                    );
+                    state.egui_renderer.context().output_mut(|output| {
+                        output.ime = Some(egui::output::IMEOutput {
+                            rect,
+                            cursor_rect: rect,
+                        });
+                    });
+                }
 
 
 
-            state.egui_renderer.end_frame_and_draw(
-                &state.device,
-                &state.queue,
-                &mut encoder,
-                window,
-                &surface_view,
-                screen_descriptor,
-            );
+            state.draw(window, pixels_per_point);
+        }
+        let presented = Instant::now();
+
+        if let Some(allow) = answer {
+            self.answer_permission_prompt(allow);
+        }
+        if let Some(identifier) = switch_to {
+            self.switch_tab(identifier);
+        }
+        if let Some(origin) = clear_origin {
+            if let Err(e) = self.storage.clear(&origin) {
+                self.current_status = format!("Failed to clear data of {}: {}", origin, e);
+            }
+            refresh_storage = true;
+        }
+        if refresh_storage && self.storage_panel.is_some() {
+            self.storage_panel = Some(self.storage.usage());
+        }
+        if let Some(rect) = page_rect {
+            let area = physical_area(rect, pixels_per_point);
+            self.page_area = Some(area);
+            if let Some(page) = self.current_wasm_page() {
+                page.place(area);
+                // only the canvas counts, a page can be smaller than the panel
+                let covered = page
+                    .area()
+                    .is_some_and(|canvas| covers(&overlays, canvas, pixels_per_point));
+                page.set_covered(covered);
+                page.update_ime();
+                page.update_view();
+            }
         }
 
-        state.queue.submit(Some(encoder.finish()));
-        surface_texture.present();
+        // a minimized window never gets here, a covered one still redraws but shows nothing
+        let keep_running = self.background_policy == BackgroundPolicy::KeepRunning;
+        for tab in &mut self.tabs {
+            let Some(page) = tab.page.as_mut() else {
+                continue;
+            };
+            if tab.identifier == self.current_tab {
+                if !self.window_occluded {
+                    page.animation_frame(presented);
+                }
+            } else if keep_running {
+                page.background_frame(presented);
+            }
+        }
+    }
+
+    /// Hand `event` to the current tab's wasm page, see `WasmPage::route_input`.
+    fn route_page_input(&mut self, event: &WindowEvent, window_id: WindowId) {
+        let egui_wants_keyboard = self
+            .state
+            .as_ref()
+            .is_some_and(|state| state.egui_renderer.context().wants_keyboard_input());
+        let main_window_focused = self.window.as_ref().is_some_and(|w| w.has_focus());
+        let from_parent = window_id == self.parent_window_id;
+        let Some(page) = self.current_wasm_page() else {
+            return;
+        };
+        let space = if window_id == page.window_id() {
+            EventSpace::Page
+        } else if from_parent {
+            EventSpace::Parent
+        } else {
+            page.canvas_window_event(window_id, event);
+            return;
+        };
+        page.route_input(event, space, egui_wants_keyboard, main_window_focused);
     }
 }
 
-impl ApplicationHandler for App {
+impl ApplicationHandler<MainThreadAction> for App {
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
         let window = event_loop
             .create_window(Window::default_attributes().with_title("M"))
@@ -592,123 +1105,226 @@ impl ApplicationHandler for App {
         pollster::block_on(self.set_window(window));
     }
 
-    fn window_event(&mut self, event_loop: &ActiveEventLoop, _: WindowId, event: WindowEvent) {
+    fn user_event(&mut self, event_loop: &ActiveEventLoop, event: MainThreadAction) {
+        match event {
+            MainThreadAction::Spawn(f, res) => {
+                // the guest may have been killed while this was queued
+                let _ = res.send(f());
+            }
+            MainThreadAction::ReadClipboard(reply) => {
+                let text = self
+                    .state
+                    .as_mut()
+                    .and_then(|state| state.egui_renderer.clipboard_text());
+                let _ = reply.send(text);
+            }
+            MainThreadAction::WriteClipboard(text) => {
+                if let Some(state) = self.state.as_mut() {
+                    state.egui_renderer.set_clipboard_text(text);
+                }
+            }
+            MainThreadAction::CreateWindow(tab, desc, reply) => {
+                // a page that is already gone is not waiting for a canvas anymore
+                let Some(tab) = self.tabs.iter_mut().find(|t| t.identifier == tab) else {
+                    let _ = reply.send(None);
+                    return;
+                };
+                let location = tab.location.clone();
+                let Some(page) = tab.page.as_mut() else {
+                    let _ = reply.send(None);
+                    return;
+                };
+                let size = LogicalSize::new(
+                    desc.width.unwrap_or(DEFAULT_CANVAS_SIZE.width),
+                    desc.height.unwrap_or(DEFAULT_CANVAS_SIZE.height),
+                );
+                let title = format!("{} - canvas {}", location, page.canvas_count() + 1);
+                let attributes = Window::default_attributes()
+                    .with_title(title)
+                    .with_inner_size(size);
+                let window = match event_loop.create_window(attributes) {
+                    Ok(window) => Arc::new(window),
+                    Err(e) => {
+                        tab.console.note(format!("Failed to open a window for another canvas: {e}"));
+                        let _ = reply.send(None);
+                        return;
+                    }
+                };
+                let surface = wasi_surface_wasmtime::Surface::new(Box::new(MyWindowWrapper(
+                    Arc::clone(&window),
+                )));
+                let mut handler = WinitEventToSurfaceProxy::new(
+                    surface.proxy(),
+                    page.handler().input(),
+                    page.handler().frames(),
+                );
+                handler.set_area(PhysicalPosition::new(0, 0), window.inner_size());
+                handler.set_scale_factor(window.scale_factor());
+                page.add_canvas_window(window, handler);
+                let _ = reply.send(Some(surface));
+            }
+        }
+    }
+
+    fn device_event(&mut self, _event_loop: &ActiveEventLoop, _device_id: DeviceId, event: DeviceEvent) {
+        // raw movement keeps coming while the pointer is locked in place
+        if let DeviceEvent::MouseMotion { delta } = event {
+            if let Some(page) = self.current_wasm_page() {
+                page.pointer_motion(delta);
+            }
+        }
+    }
+
+    fn window_event(&mut self, event_loop: &ActiveEventLoop, window_id: WindowId, event: WindowEvent) {
         let close_child_window = self.close_child_window;
         if self.quit_pressed {
             println!("Quit pressed, exiting.");
             event_loop.exit();
         }
+        let from_parent = window_id == self.parent_window_id;
+
         // let egui render to process the event first
-        self.state
-            .as_mut()
-            .unwrap()
-            .egui_renderer
-            .handle_input(self.window.as_ref().unwrap(), &event);
+        if from_parent {
+            if let Some(state) = self.state.as_mut() {
+                state
+                    .egui_renderer
+                    .handle_input(self.window.as_ref().unwrap(), &event);
+            }
+        }
+
+        self.route_page_input(&event, window_id);
 
-        if let Some(wasi_event_handler) = &mut self.wasi_event_handler {
-            wasi_event_handler.send_event(&event);
+        if self.spawn_child_window && self.current_wasm.is_none() {
+            log::debug!("No wasm component loaded in this tab, not spawning child window");
+            self.spawn_child_window = false;
         }
 
         if self.spawn_child_window {
             self.spawn_child_window = false;
-            if self.child_window.is_some() {
-                println!("About to stop wasm using wasm_runtime_events from new spawn");
-                //pollster::block_on(wasm_runtime_events.lock().expect("REASON").stop_wasm()).unwrap();
-                self.event_sender.as_ref().unwrap().send(()).unwrap();
-                self.sender.as_ref().unwrap().send(()).unwrap();
-                println!("Closing child window.");
-                self.child_window_id = 2.into(); // hide child window
-                self.child_window.clone().expect("REASON").set_visible(false);
-                self.child_window = None;
-                self.wasi_event_handler = None;
-                let (tx, rx) = mpsc::channel();
-                let (event_tx, event_rx) = mpsc::channel();
-                self.sender = Some(tx);
-                self.event_sender = Some(event_tx);
-                self.event_receiver = Some(Arc::new(Mutex::new(event_rx)));
-                self.wasm_runtime = Arc::new(Mutex::new(Wasm::new(rx).unwrap()));
-            }
-            let event_receiver = self.event_receiver.clone().unwrap();
+            self.stop_child_window();
             println!("Spawned child window.");
 
-            if self.child_window.is_some() {
-                println!("Child window already exists, closing it.");
-                self.child_window_id = 2.into(); // hide child window
-                self.child_window = None;
-                self.wasi_event_handler = None;
-            }
-            println!("M key pressed");
             //let child_window = spawn_child_window(&Arc::try_unwrap(self.window.unwrap().unwrap(), event_loop);:
-            let child_window = Arc::new(spawn_child_window(self.window.as_ref().unwrap().as_ref(), event_loop));
-            self.child_window = Some(Arc::clone(&child_window));
+            let child_window = Arc::new(spawn_child_window(
+                self.window.as_ref().unwrap().as_ref(),
+                event_loop,
+                self.page_area,
+            ));
+            // blank until the guest presents, painting it every frame would cover frame buffers
+            fill::fill_window(&child_window);
+            let scale_factor = child_window.scale_factor();
             // self.wasi_surface = Some(wasi_surface_wasmtime::Surface::new(Box::new(MyWindowWrapper(child_window))));
 
-            let surface = wasi_surface_wasmtime::Surface::new(Box::new(MyWindowWrapper(child_window)));
+            let surface = wasi_surface_wasmtime::Surface::new(Box::new(MyWindowWrapper(
+                Arc::clone(&child_window),
+            )));
 
             let surface_proxy: wasi_surface_wasmtime::SurfaceProxy = surface.proxy();
-            self.wasi_event_handler = Some(WinitEventToSurfaceProxy::new(surface_proxy.clone()));
-
-            let wasm_runtime_start = Arc::clone(&self.wasm_runtime);
-            std::thread::spawn(move || {
-                match wasm_runtime_start.lock() {
-                    Ok(mut guard) => {
-                        match pollster::block_on(guard.run_wasm("downloaded.wasm".to_string(), surface)) {
-                            Ok(_) => println!("Wasm ran successfully"),
-                            Err(e) => println!("Error running wasm: {e}"),
-                        }
-                    },
-                    Err(e) => {
-                        println!("Failed to lock wasm runtime mutex: {e}");
+            let input = InputQueue::new();
+            let frames = FrameClock::new();
+            let mut wasi_event_handler =
+                WinitEventToSurfaceProxy::new(surface_proxy, input.clone(), frames.clone());
+            if let Some((position, size)) = self.page_area {
+                wasi_event_handler.set_area(position, size);
+            }
+            wasi_event_handler.set_scale_factor(scale_factor);
+
+            let wasm_path = self.current_wasm.clone().unwrap();
+            let location = self.current_location.clone();
+            let runtime = self.runtime.clone();
+            let permissions = self.permission_sender.clone();
+            let chrome_sender = self.chrome_sender.clone();
+            let origin = origin_of(&location);
+            let storage = self.storage.for_origin(&origin);
+            let main_thread = self.main_thread.clone();
+            let area = self.page_area;
+            if let Some(tab) = self.current_tab_mut() {
+                let console = tab.console.attach();
+                let network = tab.network.attach(&origin);
+                match runtime {
+                    Some(runtime) => {
+                        let chrome = PageChrome::new(
+                            tab.identifier,
+                            location.clone(),
+                            input.activation(),
+                            chrome_sender,
+                        );
+                        let wasm = Wasm::new(
+                            &runtime,
+                            permissions,
+                            input,
+                            frames,
+                            chrome,
+                            storage,
+                            network,
+                            main_thread,
+                        );
+                        let instance = wasm.start(wasm_path, location, surface, console);
+                        log::debug!("Child window created with id: {:?}", child_window.id());
+                        tab.page = Some(WasmPage::new(
+                            instance,
+                            child_window,
+                            wasi_event_handler,
+                            area,
+                        ));
                     }
-                }
-                // pollster::block_on(wasm_runtime.lock().unwrap().run_wasm("breakout.wasm".to_string(), surface)).unwrap();
-            });
-
-
-            std::thread::spawn(move || {
-                loop {
-                    if event_receiver.lock().expect("REASON").try_recv().is_ok() {
-                        println!("Close child window signal received in thread.");
-                        break;
+                    None => {
+                        tab.status = "Failed to start wasm: the wasm runtime is unavailable".to_string();
+                        child_window.set_visible(false);
                     }
-                    surface_proxy.animation_frame();
-                    std::thread::sleep(std::time::Duration::from_millis(16));
-                    //if received.is_ok() {
-                    //    println!("Received quit signal in child window thread.");
-                    //    pollster::block_on(wasm_runtime_events.lock().unwrap().stop_wasm()).unwrap();
-                    //    break;
-                    //}
                 }
-            });
-
-            let child_id = self.child_window.as_ref().unwrap().id();
-            println!("Child window created with id: {child_id:?}");
-            self.child_window_id = child_id;
-
+                self.current_status = tab.status.clone();
+            }
         }
 
         if self.close_child_window {
             self.close_child_window = false;
-            //let wasm_runtime_events = Arc::clone(&self.wasm_runtime);
-            if self.child_window.is_some() {
-                println!("About to stop wasm using wasm_runtime_events");
-                //pollster::block_on(wasm_runtime_events.lock().expect("REASON").stop_wasm()).unwrap();
-                self.event_sender.as_ref().unwrap().send(()).unwrap();
-                self.sender.as_ref().unwrap().send(()).unwrap();
-                println!("Closing child window.");
-                self.child_window_id = 2.into(); // hide child window
-                self.child_window.clone().expect("REASON").set_visible(false);
-                self.child_window = None;
-                self.wasi_event_handler = None;
-                let (tx, rx) = mpsc::channel();
-                let (event_tx, event_rx) = mpsc::channel();
-                self.sender = Some(tx);
-                self.event_sender = Some(event_tx);
-                self.event_receiver = Some(Arc::new(Mutex::new(event_rx)));
-                self.wasm_runtime = Arc::new(Mutex::new(Wasm::new(rx).unwrap()));
+            self.stop_child_window();
+        }
+
+        // report guests that exit on their own, in any tab
+        let mut finished = Vec::new();
+        for tab in &mut self.tabs {
+            if let Some(status) = tab.page.as_mut().and_then(|page| page.instance.status()) {
+                if let Some(page) = tab.page.take() {
+                    tab.console_page = page.headless();
+                    page.stop();
+                }
+                tab.status = format!("Wasm page {}", status);
+                if tab.console_page {
+                    tab.console.note(format!("Page {}", status));
+                }
+                finished.push((tab.identifier, tab.console_page, status));
+            }
+        }
+        for (identifier, console_page, status) in finished {
+            if identifier == self.current_tab {
+                self.current_status = format!("Wasm page {}", status);
+            }
+            // a page without graphics ends in its console, which says how it ended
+            if console_page {
+                continue;
+            }
+            match &status {
+                ExitStatus::Exited(0) | ExitStatus::Killed => {}
+                ExitStatus::Exited(code) => {
+                    let details = format!("The page exited with code {}.", code);
+                    self.show_error_page(identifier, "This page exited with an error", &details);
+                }
+                ExitStatus::Failed(failure) => {
+                    self.show_error_page(identifier, failure.title(), &failure.to_markdown());
+                }
+                ExitStatus::LimitExceeded(message) => {
+                    self.show_error_page(identifier, "This page was stopped", message);
+                }
             }
         }
 
+        // the page's own window is driven by the guest, only the main window redraws egui
+        if !from_parent {
+            return;
+        }
+
         match event {
             WindowEvent::CloseRequested => {
                 println!("The close button was pressed; stopping");
@@ -718,9 +1334,8 @@ impl ApplicationHandler for App {
                 self.handle_redraw();
 
                 self.window.as_ref().unwrap().request_redraw();
-                if self.child_window_id != 2.into() {
-                    fill::fill_window(self.child_window.as_ref().unwrap());
-                    self.child_window.as_ref().unwrap().request_redraw();
+                if let Some(page) = self.current_wasm_page() {
+                    page.window().request_redraw();
                 }
                 //self.child_window.as_ref().unwrap().request_redraw();
 
@@ -728,18 +1343,35 @@ impl ApplicationHandler for App {
             WindowEvent::Resized(new_size) => {
                 self.handle_resized(new_size.width, new_size.height);
             }
+            WindowEvent::Occluded(occluded) => {
+                self.window_occluded = occluded;
+            }
             _ => (),
         }
     }
 }
 
-fn spawn_child_window(parent: &Window, event_loop: &ActiveEventLoop) -> Window {
+/// Size of a further canvas window when the page does not ask for one, in logical pixels.
+const DEFAULT_CANVAS_SIZE: LogicalSize<u32> = LogicalSize::new(640, 480);
+
+fn spawn_child_window(
+    parent: &Window,
+    event_loop: &ActiveEventLoop,
+    area: Option<(PhysicalPosition<i32>, PhysicalSize<u32>)>,
+) -> Window {
     let parent = parent.raw_window_handle().unwrap();
     let mut window_attributes = Window::default_attributes()
         .with_title("child window")
-        .with_inner_size(LogicalSize::new(1080.0f32, 720.0f32))
-        .with_position(Position::Logical(LogicalPosition::new(200.0, 27.0)))
+        .with_decorations(false)
         .with_visible(true);
+    window_attributes = match area {
+        Some((position, size)) => window_attributes
+            .with_inner_size(size)
+            .with_position(position),
+        None => window_attributes
+            .with_inner_size(LogicalSize::new(1080.0f32, 720.0f32))
+            .with_position(Position::Logical(LogicalPosition::new(200.0, 27.0))),
+    };
     // `with_parent_window` is unsafe. Parent window must be a valid window.
     window_attributes = unsafe { window_attributes.with_parent_window(Some(parent)) };
 
@@ -758,45 +1390,48 @@ pub fn forward() -> Result<(), String> {
     println!("Forward button pressed");
     Ok(())
 }
-pub fn go(url: String) -> String {
-    // Implement go logic here
-    println!("Going to URL: {}", url);
-    println!("Go button pressed");
-    let resp = navigate(url);
-    return resp;
-}
-
-pub fn navigate(location: String) -> String {
-    // Implement navigation logic here
-    // For now, just return Ok
-    println!("Navigating to URL: {}", location);
-    if is_wasm(location.clone()) {
-        println!("Downloading wasm file");
-        let wasm_file = download_wasm(location.clone());
-        return wasm_file;
-    }
-    let resp = reqwest::blocking::get(location.clone())
-        .and_then(|r| r.text())
-        .map_err(|e| e.to_string());
 
-    //println!("{:#?}", resp);
-    //self.current_status = "Loaded".to_string();
-    return resp.unwrap_or_else(|_| "Failed to load page".to_string());
+/// Convert a rect in egui points to a position and size in physical pixels.
+fn physical_area(rect: egui::Rect, pixels_per_point: f32) -> (PhysicalPosition<i32>, PhysicalSize<u32>) {
+    let position = PhysicalPosition::new(
+        (rect.min.x * pixels_per_point).round() as i32,
+        (rect.min.y * pixels_per_point).round() as i32,
+    );
+    let size = PhysicalSize::new(
+        (rect.width() * pixels_per_point).round().max(1.0) as u32,
+        (rect.height() * pixels_per_point).round().max(1.0) as u32,
+    );
+    (position, size)
 }
 
-pub fn download_wasm(url: String) -> String {
-    let resp = reqwest::blocking::get(url.clone())
-        .and_then(|r| r.bytes())
-        .map_err(|e| e.to_string());
-    let wasm_bytes = resp.unwrap();
-    // save wasm_bytes to a file
-    // let wasm_path = format!("./{}.wasm", url.replace("https://", "").replace("/", "_"));
-    let wasm_path = "downloaded.wasm".to_string();
-    std::fs::write(&wasm_path, wasm_bytes).unwrap_or_else(|_| {
-        eprintln!("Failed to write wasm file to {}", wasm_path);
-    });
-    return wasm_path;
+/// Where egui drew windows, menus, popups and tooltips, in points. Panels live in the
+/// background layer and are left out.
+fn egui_overlays(ctx: &egui::Context) -> Vec<egui::Rect> {
+    ctx.memory(|memory| {
+        memory
+            .areas()
+            .visible_layer_ids()
+            .into_iter()
+            .filter(|layer| layer.order != egui::Order::Background)
+            .filter_map(|layer| memory.area_rect(layer.id))
+            .collect()
+    })
+}
 
+/// Whether any of `overlays` reaches into `canvas`, in physical pixels. One that only
+/// touches its edge, like a menu dropping down to the top of the panel, does not.
+fn covers(
+    overlays: &[egui::Rect],
+    (position, size): (PhysicalPosition<i32>, PhysicalSize<u32>),
+    pixels_per_point: f32,
+) -> bool {
+    let canvas = egui::Rect::from_min_size(
+        egui::pos2(position.x as f32, position.y as f32) / pixels_per_point,
+        egui::vec2(size.width as f32, size.height as f32) / pixels_per_point,
+    );
+    overlays
+        .iter()
+        .any(|overlay| overlay.intersect(canvas).is_positive())
 }
 
 fn get_heading(location: String, contents: String) -> String {
@@ -828,10 +1463,3 @@ fn get_heading(location: String, contents: String) -> String {
 
     heading
 }
-
-fn is_wasm(filename: String) -> bool {
-    if filename.ends_with(".wasm") {
-        return true;
-    }
-    false
-}
@@ -0,0 +1,463 @@
+//! On-disk cache of downloaded wasm components and core modules.
+//!
+//! Components are stored under the user's cache directory by the SHA-256 of their bytes, so two
+//! tabs with different components never share a file and an identical component downloaded from
+//! two URLs is only stored once. A small pointer file per source URL records which content hash
+//! that URL served last. When the cache grows past its size limit the least recently used
+//! components are removed first.
+//!
+//! Compiled components and modules live next to them in a second cache, keyed by the hash of
+//! their bytes and the engine's compatibility hash, so a page that was already visited skips
+//! Cranelift entirely.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+use wasmtime::component::Component;
+use wasmtime::{Engine, Module};
+
+const DEFAULT_MAX_BYTES: u64 = 512 * 1024 * 1024;
+const DEFAULT_MAX_COMPILED_BYTES: u64 = 1024 * 1024 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct ComponentCache {
+    root: PathBuf,
+    max_bytes: u64,
+}
+
+impl ComponentCache {
+    /// Open the cache in the platform cache directory, e.g. `~/.cache/m-browser/components`.
+    pub fn open_default() -> io::Result<Self> {
+        let base = dirs::cache_dir().unwrap_or_else(std::env::temp_dir);
+        Self::open(base.join("m-browser").join("components"), DEFAULT_MAX_BYTES)
+    }
+
+    pub fn open(root: PathBuf, max_bytes: u64) -> io::Result<Self> {
+        fs::create_dir_all(root.join("sources"))?;
+        Ok(Self { root, max_bytes })
+    }
+
+    /// Store `bytes` downloaded from `source_url` and return the path of the cached component.
+    pub fn insert(&self, source_url: &str, bytes: &[u8]) -> io::Result<PathBuf> {
+        let hash = hex_digest(bytes);
+        let path = self.component_path(&hash);
+        if path.exists() {
+            touch(&path)?;
+        } else {
+            // write then rename, so a concurrent reader never sees a partial component
+            let partial = partial_path(&path);
+            fs::write(&partial, bytes)?;
+            fs::rename(&partial, &path)?;
+        }
+        let source = self.source_path(source_url);
+        fs::write(&source, format!("{}\n{}\n", hash, source_url))?;
+
+        // source pointers count against the limit too, they go oldest first like components
+        let mut entries = cache_entries(&self.root, Some("wasm"));
+        entries.extend(cache_entries(&self.root.join("sources"), None));
+        evict(entries, self.max_bytes, &[&path, &source]);
+        Ok(path)
+    }
+
+    /// The component last downloaded from `source_url`, if it is still cached.
+    pub fn lookup(&self, source_url: &str) -> Option<PathBuf> {
+        let pointer = fs::read_to_string(self.source_path(source_url)).ok()?;
+        let (hash, url) = pointer.split_once('\n')?;
+        if url.trim_end() != source_url {
+            return None;
+        }
+        let path = self.component_path(hash);
+        if !path.exists() {
+            return None;
+        }
+        let _ = touch(&path);
+        Some(path)
+    }
+
+    fn component_path(&self, hash: &str) -> PathBuf {
+        self.root.join(format!("{}.wasm", hash))
+    }
+
+    fn source_path(&self, source_url: &str) -> PathBuf {
+        self.root.join("sources").join(hex_digest(source_url.as_bytes()))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CompiledCache {
+    root: PathBuf,
+    max_bytes: u64,
+}
+
+impl CompiledCache {
+    /// Open the cache in the platform cache directory, e.g. `~/.cache/m-browser/compiled`.
+    pub fn open_default() -> io::Result<Self> {
+        let base = dirs::cache_dir().unwrap_or_else(std::env::temp_dir);
+        Self::open(base.join("m-browser").join("compiled"), DEFAULT_MAX_COMPILED_BYTES)
+    }
+
+    pub fn open(root: PathBuf, max_bytes: u64) -> io::Result<Self> {
+        fs::create_dir_all(&root)?;
+        Ok(Self { root, max_bytes })
+    }
+
+    /// Load the compiled form of the component or module at `wasm_path`, compiling and storing
+    /// it first if there is no usable artifact for this engine.
+    ///
+    /// Artifacts that fail their checksum or that wasmtime refuses to deserialize are thrown away
+    /// and rebuilt.
+    pub fn load_or_compile<T: Compiled>(&self, engine: &Engine, wasm_path: &Path) -> anyhow::Result<T> {
+        let bytes = fs::read(wasm_path).context("Component file not found")?;
+        let path = self.root.join(format!(
+            "{}-{}.cwasm",
+            hex_digest(&bytes),
+            engine_key(engine)
+        ));
+
+        match self.load(engine, &path) {
+            Ok(Some(component)) => {
+                let _ = touch(&path);
+                return Ok(component);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                log::warn!("Discarding compiled component {}: {:#}", path.display(), e);
+                let _ = fs::remove_file(&path);
+            }
+        }
+
+        let artifact = T::precompile(engine, &bytes)?;
+        if let Err(e) = self.store(&path, &artifact) {
+            log::warn!("Failed to store compiled component: {}", e);
+        }
+        // SAFETY: the artifact was produced by `T::precompile` on this engine just now.
+        unsafe { T::deserialize(engine, &artifact) }
+    }
+
+    fn load<T: Compiled>(&self, engine: &Engine, path: &Path) -> anyhow::Result<Option<T>> {
+        let stored = match fs::read(path) {
+            Ok(stored) => stored,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        // artifacts are stored as a sha-256 of the payload followed by the payload itself
+        if stored.len() < 32 {
+            anyhow::bail!("artifact is truncated");
+        }
+        let (checksum, artifact) = stored.split_at(32);
+        if Sha256::digest(artifact).as_slice() != checksum {
+            anyhow::bail!("artifact checksum mismatch");
+        }
+        // SAFETY: the checksum matches what `store` wrote, which only ever writes artifacts
+        // from `Compiled::precompile`. wasmtime itself rejects artifacts built for a different
+        // engine configuration or version, or of the other kind.
+        let compiled = unsafe { T::deserialize(engine, artifact) }?;
+        Ok(Some(compiled))
+    }
+
+    fn store(&self, path: &Path, artifact: &[u8]) -> io::Result<()> {
+        let mut stored = Sha256::digest(artifact).to_vec();
+        stored.extend_from_slice(artifact);
+        let partial = partial_path(path);
+        fs::write(&partial, stored)?;
+        fs::rename(&partial, path)?;
+        evict(cache_entries(&self.root, Some("cwasm")), self.max_bytes, &[path]);
+        Ok(())
+    }
+}
+
+/// Something `CompiledCache` can hold: a component or a core module.
+pub trait Compiled: Sized {
+    fn precompile(engine: &Engine, bytes: &[u8]) -> anyhow::Result<Vec<u8>>;
+
+    /// # Safety
+    ///
+    /// `artifact` must come from `precompile`, see `Component::deserialize`.
+    unsafe fn deserialize(engine: &Engine, artifact: &[u8]) -> anyhow::Result<Self>;
+}
+
+impl Compiled for Component {
+    fn precompile(engine: &Engine, bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+        engine
+            .precompile_component(bytes)
+            .context("failed to compile component")
+    }
+
+    unsafe fn deserialize(engine: &Engine, artifact: &[u8]) -> anyhow::Result<Self> {
+        Component::deserialize(engine, artifact)
+    }
+}
+
+impl Compiled for Module {
+    fn precompile(engine: &Engine, bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+        engine
+            .precompile_module(bytes)
+            .context("failed to compile module")
+    }
+
+    unsafe fn deserialize(engine: &Engine, artifact: &[u8]) -> anyhow::Result<Self> {
+        Module::deserialize(engine, artifact)
+    }
+}
+
+/// Identifies everything about the engine that affects compiled code: wasmtime version,
+/// target and the compilation settings in its `Config`.
+fn engine_key(engine: &Engine) -> String {
+    let mut hasher = DefaultHasher::new();
+    engine.precompile_compatibility_hash().hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// A cached file: when it was last used, its size and where it is.
+type CacheEntry = (SystemTime, u64, PathBuf);
+
+/// The files directly in `dir`, only those with extension `ext` if given. Files that vanish or
+/// cannot be read while listing are left out.
+fn cache_entries(dir: &Path, ext: Option<&str>) -> Vec<CacheEntry> {
+    let Ok(dir) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    dir.filter_map(|entry| entry.ok())
+        .filter(|entry| ext.is_none() || entry.path().extension().and_then(|e| e.to_str()) == ext)
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            if !metadata.is_file() {
+                return None;
+            }
+            let used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            Some((used, metadata.len(), entry.path()))
+        })
+        .collect()
+}
+
+/// Remove the least recently used of `entries` until the rest fit in `max_bytes`.
+///
+/// `keep` is never removed, it holds the entries that are about to be used. A file that cannot
+/// be removed is skipped, the next one goes instead.
+fn evict(mut entries: Vec<CacheEntry>, max_bytes: u64, keep: &[&Path]) {
+    let mut total: u64 = entries.iter().map(|(_, len, _)| len).sum();
+    entries.sort_by_key(|(used, _, _)| *used);
+
+    for (_, len, path) in entries {
+        if total <= max_bytes {
+            break;
+        }
+        if keep.contains(&path.as_path()) {
+            continue;
+        }
+        // source pointers to a removed component are ignored by `ComponentCache::lookup`
+        match fs::remove_file(&path) {
+            Ok(()) => total -= len,
+            Err(e) => log::warn!("Failed to evict {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// A name to write `path`'s contents to before renaming them into place, unique to this call
+/// so that two writers of the same entry never share it.
+fn partial_path(path: &Path) -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".partial-{}-{}", std::process::id(), n));
+    path.with_file_name(name)
+}
+
+pub fn hex_digest(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn touch(path: &Path) -> io::Result<()> {
+    fs::File::options()
+        .append(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    /// An empty directory of its own for one test.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("m-browser-cache-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    /// Write `len` bytes to `dir/name`, last used `age` seconds ago.
+    fn entry(dir: &Path, name: &str, len: usize, age: u64) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, vec![0; len]).unwrap();
+        let used = SystemTime::now() - Duration::from_secs(age);
+        fs::File::options().append(true).open(&path).unwrap().set_modified(used).unwrap();
+        path
+    }
+
+    /// The smallest valid core module.
+    const MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+    /// A compiled cache holding nothing yet, and a module for it to compile.
+    fn compiled_cache(name: &str) -> (CompiledCache, PathBuf) {
+        let dir = scratch_dir(name);
+        let wasm_path = dir.join("page.wasm");
+        fs::write(&wasm_path, MODULE).unwrap();
+        let cache = CompiledCache::open(dir.join("compiled"), 1024 * 1024 * 1024).unwrap();
+        (cache, wasm_path)
+    }
+
+    fn artifacts(cache: &CompiledCache) -> Vec<PathBuf> {
+        cache_entries(&cache.root, Some("cwasm"))
+            .into_iter()
+            .map(|(_, _, path)| path)
+            .collect()
+    }
+
+    #[test]
+    fn compiled_artifacts_are_stored_and_loaded() {
+        let engine = Engine::default();
+        let (cache, wasm_path) = compiled_cache("compiled");
+        cache.load_or_compile::<Module>(&engine, &wasm_path).unwrap();
+        let stored = artifacts(&cache);
+        assert_eq!(stored.len(), 1);
+
+        let path = &stored[0];
+        assert!(cache.load::<Module>(&engine, path).unwrap().is_some());
+        assert!(cache.load::<Module>(&engine, &path.with_extension("missing")).unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_artifacts_are_rejected_and_rebuilt() {
+        let engine = Engine::default();
+        let (cache, wasm_path) = compiled_cache("truncated");
+        cache.load_or_compile::<Module>(&engine, &wasm_path).unwrap();
+        let path = artifacts(&cache).remove(0);
+        let stored = fs::read(&path).unwrap();
+
+        fs::write(&path, &stored[..16]).unwrap();
+        assert!(cache.load::<Module>(&engine, &path).is_err());
+        fs::write(&path, &stored[..stored.len() - 1]).unwrap();
+        assert!(cache.load::<Module>(&engine, &path).is_err());
+
+        cache.load_or_compile::<Module>(&engine, &wasm_path).unwrap();
+        assert!(cache.load::<Module>(&engine, &path).unwrap().is_some());
+    }
+
+    #[test]
+    fn corrupt_artifacts_are_rejected_and_rebuilt() {
+        let engine = Engine::default();
+        let (cache, wasm_path) = compiled_cache("corrupt");
+        cache.load_or_compile::<Module>(&engine, &wasm_path).unwrap();
+        let path = artifacts(&cache).remove(0);
+        let stored = fs::read(&path).unwrap();
+
+        let mut corrupt = stored.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xff;
+        fs::write(&path, &corrupt).unwrap();
+        assert!(cache.load::<Module>(&engine, &path).is_err());
+
+        cache.load_or_compile::<Module>(&engine, &wasm_path).unwrap();
+        assert!(cache.load::<Module>(&engine, &path).unwrap().is_some());
+    }
+
+    #[test]
+    fn a_changed_engine_does_not_use_older_artifacts() {
+        let engine = Engine::default();
+        let mut config = wasmtime::Config::new();
+        config.cranelift_opt_level(wasmtime::OptLevel::None);
+        let changed = Engine::new(&config).unwrap();
+        assert_ne!(engine_key(&engine), engine_key(&changed));
+
+        let (cache, wasm_path) = compiled_cache("engine");
+        cache.load_or_compile::<Module>(&engine, &wasm_path).unwrap();
+        cache.load_or_compile::<Module>(&changed, &wasm_path).unwrap();
+        let stored = artifacts(&cache);
+        assert_eq!(stored.len(), 2);
+        for key in [engine_key(&engine), engine_key(&changed)] {
+            assert!(stored.iter().any(|path| path.to_str().unwrap().ends_with(&format!("-{}.cwasm", key))));
+        }
+    }
+
+    #[test]
+    fn eviction_removes_the_least_recently_used_first() {
+        let dir = scratch_dir("evict");
+        let oldest = entry(&dir, "a.wasm", 100, 30);
+        let older = entry(&dir, "b.wasm", 100, 20);
+        let newest = entry(&dir, "c.wasm", 100, 10);
+
+        evict(cache_entries(&dir, Some("wasm")), 150, &[]);
+        assert!(!oldest.exists());
+        assert!(!older.exists());
+        assert!(newest.exists());
+    }
+
+    #[test]
+    fn eviction_never_removes_what_is_kept() {
+        let dir = scratch_dir("keep");
+        let oldest = entry(&dir, "a.wasm", 100, 30);
+        let newer = entry(&dir, "b.wasm", 100, 20);
+
+        evict(cache_entries(&dir, Some("wasm")), 150, &[&oldest]);
+        assert!(oldest.exists());
+        assert!(!newer.exists());
+    }
+
+    #[test]
+    fn eviction_only_counts_entries_with_the_extension() {
+        let dir = scratch_dir("extension");
+        let other = entry(&dir, "notes.txt", 1000, 30);
+        let component = entry(&dir, "a.wasm", 100, 20);
+
+        evict(cache_entries(&dir, Some("wasm")), 150, &[]);
+        assert!(other.exists());
+        assert!(component.exists());
+    }
+
+    #[test]
+    fn sources_point_at_the_component_they_served_last() {
+        let cache = ComponentCache::open(scratch_dir("sources"), 1024 * 1024).unwrap();
+        let first = cache.insert("https://example.com/page.wasm", b"first").unwrap();
+        let shared = cache.insert("https://example.org/page.wasm", b"first").unwrap();
+        assert_eq!(first, shared);
+        assert_eq!(cache.lookup("https://example.com/page.wasm"), Some(first.clone()));
+
+        let second = cache.insert("https://example.com/page.wasm", b"second").unwrap();
+        assert_ne!(first, second);
+        assert_eq!(cache.lookup("https://example.com/page.wasm"), Some(second));
+        assert_eq!(cache.lookup("https://example.org/page.wasm"), Some(first));
+        assert_eq!(cache.lookup("https://example.net/page.wasm"), None);
+    }
+
+    #[test]
+    fn sources_of_evicted_components_are_not_found() {
+        let cache = ComponentCache::open(scratch_dir("evicted"), 1024 * 1024).unwrap();
+        let path = cache.insert("https://example.com/page.wasm", b"component").unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(cache.lookup("https://example.com/page.wasm"), None);
+    }
+
+    #[test]
+    fn sources_for_another_url_are_not_followed() {
+        let cache = ComponentCache::open(scratch_dir("mismatch"), 1024 * 1024).unwrap();
+        let path = cache.insert("https://example.com/page.wasm", b"component").unwrap();
+        // a pointer file whose recorded URL differs, as after a hash collision
+        let hash = path.file_stem().unwrap().to_str().unwrap();
+        let source = cache.source_path("https://example.org/page.wasm");
+        fs::write(source, format!("{}\nhttps://example.com/page.wasm\n", hash)).unwrap();
+        assert_eq!(cache.lookup("https://example.org/page.wasm"), None);
+    }
+}
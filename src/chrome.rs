@@ -0,0 +1,117 @@
+//! Page control over the browser chrome.
+//!
+//! A page calls `m:browser/chrome` from its own thread. `PageChrome` turns those calls into
+//! `ChromeRequest`s tagged with the page's tab, and `App` applies them to the tab, the status
+//! bar and the navigator on the next frame. Like a popup, a new tab needs the page's
+//! `UserActivation`.
+
+use std::sync::mpsc;
+
+use crate::host::chrome;
+use crate::input::UserActivation;
+
+/// Longest title or status a page may set, in characters. Anything beyond is cut off.
+const MAX_TEXT_CHARS: usize = 256;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChromeAction {
+    Navigate(String),
+    OpenTab(String),
+    SetTitle(String),
+    SetStatus(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct ChromeRequest {
+    pub tab: i32,
+    pub action: ChromeAction,
+}
+
+/// A page's end of `m:browser/chrome`.
+#[derive(Clone)]
+pub struct PageChrome {
+    tab: i32,
+    location: String,
+    activation: UserActivation,
+    requests: mpsc::Sender<ChromeRequest>,
+}
+
+impl PageChrome {
+    pub fn new(
+        tab: i32,
+        location: String,
+        activation: UserActivation,
+        requests: mpsc::Sender<ChromeRequest>,
+    ) -> Self {
+        Self {
+            tab,
+            location,
+            activation,
+            requests,
+        }
+    }
+
+    /// The tab the page runs in.
+    pub fn tab(&self) -> i32 {
+        self.tab
+    }
+
+    fn send(&self, action: ChromeAction) {
+        let request = ChromeRequest {
+            tab: self.tab,
+            action,
+        };
+        // the browser is shutting down if nobody listens
+        let _ = self.requests.send(request);
+    }
+
+    /// `url` relative to the page's location, as long as the navigator can load it.
+    fn resolve(&self, url: &str) -> Result<String, String> {
+        let base = reqwest::Url::parse(&self.location).ok();
+        let resolved = reqwest::Url::options()
+            .base_url(base.as_ref())
+            .parse(url)
+            .map_err(|e| format!("invalid url {:?}: {}", url, e))?;
+        match resolved.scheme() {
+            "http" | "https" => Ok(resolved.to_string()),
+            scheme => Err(format!("cannot load {} urls", scheme)),
+        }
+    }
+}
+
+fn clean_text(text: String) -> String {
+    text.chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_TEXT_CHARS)
+        .collect()
+}
+
+impl chrome::Host for PageChrome {
+    fn current_location(&mut self) -> String {
+        self.location.clone()
+    }
+
+    fn navigate(&mut self, url: String) -> Result<(), String> {
+        let url = self.resolve(&url)?;
+        self.send(ChromeAction::Navigate(url));
+        Ok(())
+    }
+
+    fn open_tab(&mut self, url: String) -> Result<(), String> {
+        // each press in the page opens at most one tab
+        if !self.activation.focused() || !self.activation.consume() {
+            return Err("tabs can only be opened right after a click in the page".to_string());
+        }
+        let url = self.resolve(&url)?;
+        self.send(ChromeAction::OpenTab(url));
+        Ok(())
+    }
+
+    fn set_title(&mut self, title: String) {
+        self.send(ChromeAction::SetTitle(clean_text(title)));
+    }
+
+    fn set_status(&mut self, status: String) {
+        self.send(ChromeAction::SetStatus(clean_text(status)));
+    }
+}
@@ -0,0 +1,46 @@
+//! Clipboard access for wasm pages, `m:browser/clipboard`.
+//!
+//! The clipboard belongs to egui on the main thread, so the page's thread asks for it with a
+//! `MainThreadAction` and waits for the answer. Whether it may ask at all is decided by the
+//! page's `UserActivation`.
+
+use crate::host::clipboard::{self, ClipboardError};
+use crate::input::UserActivation;
+use crate::winit_wasi::WasiWinitEventLoopProxy;
+
+/// A page's end of `m:browser/clipboard`.
+pub struct PageClipboard {
+    activation: UserActivation,
+    main_thread: WasiWinitEventLoopProxy,
+}
+
+impl PageClipboard {
+    pub fn new(activation: UserActivation, main_thread: WasiWinitEventLoopProxy) -> Self {
+        Self {
+            activation,
+            main_thread,
+        }
+    }
+}
+
+impl clipboard::Host for PageClipboard {
+    fn read_text(&mut self) -> Result<String, ClipboardError> {
+        if !self.activation.consume() {
+            return Err(ClipboardError::NotAllowed);
+        }
+        self.main_thread
+            .read_clipboard()
+            .ok_or(ClipboardError::Unavailable)
+    }
+
+    fn write_text(&mut self, text: String) -> Result<(), ClipboardError> {
+        if !self.activation.focused() {
+            return Err(ClipboardError::NotAllowed);
+        }
+        if self.main_thread.write_clipboard(text) {
+            Ok(())
+        } else {
+            Err(ClipboardError::Unavailable)
+        }
+    }
+}
@@ -0,0 +1,336 @@
+//! Developer console for wasm pages.
+//!
+//! Each tab owns a `Console`. When a page starts, the console hands out `ConsolePipes` whose
+//! streams become the guest's stdout and stderr. What the guest writes is sent to the console
+//! in chunks as it comes, and every frame the new chunks are split into timestamped lines. The
+//! panel keeps the latest `MAX_LINES`, can filter by stream, copy what is shown and clear.
+//!
+//! The browser adds its own notes about the page, such as why it stopped, as `Browser` lines.
+
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use wasmtime_wasi::pipe::AsyncWriteStream;
+use wasmtime_wasi::AsyncStdoutStream;
+
+/// Lines kept in the panel. The oldest are dropped first.
+const MAX_LINES: usize = 10_000;
+/// Longest line kept whole, in bytes. Output without a newline for longer is shown in pieces.
+const MAX_LINE_BYTES: usize = 64 * 1024;
+/// Bytes the guest may write before it waits for them to reach the console.
+const WRITE_BUDGET: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+    /// Written by the browser, not the page.
+    Browser,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConsoleLine {
+    /// Time since the page was started.
+    pub time: Duration,
+    pub stream: Stream,
+    pub text: String,
+}
+
+/// The guest's ends of the console, passed into its `WasiCtx`.
+#[derive(Clone)]
+pub struct ConsolePipes {
+    sender: mpsc::Sender<(Stream, Vec<u8>)>,
+}
+
+impl ConsolePipes {
+    pub fn stdout(&self) -> AsyncStdoutStream {
+        self.stream(Stream::Stdout)
+    }
+
+    pub fn stderr(&self) -> AsyncStdoutStream {
+        self.stream(Stream::Stderr)
+    }
+
+    fn stream(&self, stream: Stream) -> AsyncStdoutStream {
+        let writer = ConsoleWriter {
+            stream,
+            sender: self.sender.clone(),
+        };
+        AsyncStdoutStream::new(AsyncWriteStream::new(WRITE_BUDGET, writer))
+    }
+}
+
+/// Hands every write on to the console, which never blocks the guest.
+struct ConsoleWriter {
+    stream: Stream,
+    sender: mpsc::Sender<(Stream, Vec<u8>)>,
+}
+
+impl tokio::io::AsyncWrite for ConsoleWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        bytes: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        // once the tab is gone nobody reads the output anymore, which is not the guest's problem
+        let _ = self.sender.send((self.stream, bytes.to_vec()));
+        Poll::Ready(Ok(bytes.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+pub struct Console {
+    output: Option<mpsc::Receiver<(Stream, Vec<u8>)>>,
+    started: Instant,
+    // the unterminated tail of each stream
+    partial: [Vec<u8>; 2],
+    lines: VecDeque<ConsoleLine>,
+    show_stdout: bool,
+    show_stderr: bool,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        Self {
+            output: None,
+            started: Instant::now(),
+            partial: [Vec::new(), Vec::new()],
+            lines: VecDeque::new(),
+            show_stdout: true,
+            show_stderr: true,
+        }
+    }
+
+    /// Start capturing a new page, keeping what earlier pages printed.
+    pub fn attach(&mut self) -> ConsolePipes {
+        self.poll();
+        self.flush_partial();
+        let (sender, receiver) = mpsc::channel();
+        self.output = Some(receiver);
+        self.started = Instant::now();
+        ConsolePipes { sender }
+    }
+
+    /// Pick up anything the guest wrote since the last call.
+    pub fn poll(&mut self) {
+        let Some(output) = self.output.take() else {
+            return;
+        };
+        let time = self.started.elapsed();
+        while let Ok((stream, bytes)) = output.try_recv() {
+            self.receive(stream, &bytes, time);
+        }
+        self.output = Some(output);
+    }
+
+    /// Add a line from the browser, e.g. how the page ended.
+    pub fn note(&mut self, text: impl Into<String>) {
+        self.poll();
+        self.push(ConsoleLine {
+            time: self.started.elapsed(),
+            stream: Stream::Browser,
+            text: text.into(),
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.partial = [Vec::new(), Vec::new()];
+    }
+
+    fn push(&mut self, line: ConsoleLine) {
+        if self.lines.len() == MAX_LINES {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    fn receive(&mut self, stream: Stream, bytes: &[u8], time: Duration) {
+        let pending = &mut self.partial[stream as usize];
+        pending.extend_from_slice(bytes);
+        // complete lines are decoded as a whole, so characters split across writes survive
+        let end = match pending.iter().rposition(|&byte| byte == b'\n') {
+            Some(end) => end + 1,
+            None if pending.len() > MAX_LINE_BYTES => pending.len(),
+            None => return,
+        };
+        let complete: Vec<u8> = pending.drain(..end).collect();
+        let text = String::from_utf8_lossy(&complete);
+        let (lines, rest) = split_lines(&text);
+        for text in lines.into_iter().chain((!rest.is_empty()).then_some(rest)) {
+            self.push(ConsoleLine {
+                time,
+                stream,
+                text: text.to_string(),
+            });
+        }
+    }
+
+    fn flush_partial(&mut self) {
+        let time = self.started.elapsed();
+        for stream in [Stream::Stdout, Stream::Stderr] {
+            let bytes = std::mem::take(&mut self.partial[stream as usize]);
+            if !bytes.is_empty() {
+                let text = String::from_utf8_lossy(&bytes).into_owned();
+                self.push(ConsoleLine { time, stream, text });
+            }
+        }
+    }
+
+    fn visible(&self, line: &ConsoleLine) -> bool {
+        match line.stream {
+            Stream::Stdout => self.show_stdout,
+            Stream::Stderr => self.show_stderr,
+            Stream::Browser => true,
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.show_stdout, "stdout");
+            ui.checkbox(&mut self.show_stderr, "stderr");
+            if ui.button("Copy").clicked() {
+                let text: Vec<String> = self
+                    .lines
+                    .iter()
+                    .filter(|line| self.visible(line))
+                    .map(format_line)
+                    .collect();
+                ui.ctx().copy_text(text.join("\n"));
+            }
+            if ui.button("Clear").clicked() {
+                self.clear();
+            }
+        });
+        ui.separator();
+
+        let error_color = ui.visuals().error_fg_color;
+        egui::ScrollArea::vertical()
+            .auto_shrink([false, false])
+            .stick_to_bottom(true)
+            .show(ui, |ui| {
+                for line in self.lines.iter().filter(|line| self.visible(line)) {
+                    let mut text = egui::RichText::new(format_line(line)).monospace();
+                    match line.stream {
+                        Stream::Stderr => text = text.color(error_color),
+                        Stream::Browser => text = text.weak().italics(),
+                        Stream::Stdout => {}
+                    }
+                    ui.label(text);
+                }
+            });
+    }
+}
+
+/// The complete lines in `text`, without their `\n` or `\r\n`, and the unterminated rest.
+fn split_lines(text: &str) -> (Vec<&str>, &str) {
+    let mut lines = Vec::new();
+    let mut rest = text;
+    while let Some(end) = rest.find('\n') {
+        lines.push(rest[..end].trim_end_matches('\r'));
+        rest = &rest[end + 1..];
+    }
+    (lines, rest)
+}
+
+fn format_line(line: &ConsoleLine) -> String {
+    let stream = match line.stream {
+        Stream::Stdout => "out",
+        Stream::Stderr => "err",
+        Stream::Browser => "---",
+    };
+    format!(
+        "[{:>8.3}] {} {}",
+        line.time.as_secs_f64(),
+        stream,
+        line.text
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_complete_lines() {
+        assert_eq!(split_lines("one\ntwo\r\n"), (vec!["one", "two"], ""));
+        assert_eq!(split_lines("\n\n"), (vec!["", ""], ""));
+    }
+
+    #[test]
+    fn keeps_the_unterminated_tail() {
+        assert_eq!(split_lines("one\ntw"), (vec!["one"], "tw"));
+        assert_eq!(split_lines("no newline"), (vec![], "no newline"));
+    }
+
+    fn stdout_lines(console: &Console) -> Vec<&str> {
+        console
+            .lines
+            .iter()
+            .filter(|line| line.stream == Stream::Stdout)
+            .map(|line| line.text.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn output_keeps_arriving_past_any_buffer_size() {
+        use tokio::io::AsyncWriteExt;
+
+        let mut console = Console::new();
+        let pipes = console.attach();
+        let mut writer = ConsoleWriter {
+            stream: Stream::Stdout,
+            sender: pipes.sender.clone(),
+        };
+        let line = format!("{}\n", "x".repeat(1023));
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        // 16 MiB, twice what the in-memory pipe used to hold
+        for _ in 0..16 * 1024 {
+            runtime.block_on(writer.write_all(line.as_bytes())).unwrap();
+            console.poll();
+        }
+        runtime.block_on(writer.write_all(b"last\n")).unwrap();
+        console.poll();
+        assert_eq!(console.lines.len(), MAX_LINES);
+        assert_eq!(console.lines.back().unwrap().text, "last");
+    }
+
+    #[test]
+    fn characters_split_across_writes_survive() {
+        let mut console = Console::new();
+        let bytes = "caf\u{e9}\n".as_bytes();
+        console.receive(Stream::Stdout, &bytes[..4], Duration::ZERO);
+        console.receive(Stream::Stdout, &bytes[4..], Duration::ZERO);
+        assert_eq!(stdout_lines(&console), vec!["caf\u{e9}"]);
+    }
+
+    #[test]
+    fn overlong_lines_are_shown_in_pieces() {
+        let mut console = Console::new();
+        console.receive(Stream::Stdout, &vec![b'x'; MAX_LINE_BYTES + 1], Duration::ZERO);
+        assert_eq!(stdout_lines(&console).len(), 1);
+        assert!(console.partial[0].is_empty());
+    }
+
+    #[test]
+    fn notes_are_always_shown() {
+        let mut console = Console::new();
+        console.show_stdout = false;
+        console.show_stderr = false;
+        console.note("stopped");
+        assert_eq!(console.lines.len(), 1);
+        assert!(console.visible(&console.lines[0]));
+        assert_eq!(console.lines[0].stream, Stream::Browser);
+    }
+}
@@ -0,0 +1,165 @@
+//! Decide how a fetched resource should be rendered.
+//!
+//! The URL says very little about what a server actually sent back, so the decision is made from
+//! the body itself first and the `Content-Type` header second. A wasm binary always starts with
+//! `\0asm` followed by a 16-bit version and a 16-bit layer; layer 0 is a core module and layer 1
+//! is a component.
+
+use std::fmt;
+
+const WASM_MAGIC: [u8; 4] = *b"\0asm";
+const CORE_LAYER: u16 = 0;
+const COMPONENT_LAYER: u16 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentKind {
+    Markdown,
+    WasmComponent,
+    WasmModule,
+    Binary,
+}
+
+/// What decided the content kind, reported in the status bar.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Evidence {
+    MagicBytes,
+    ContentType,
+    Default,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Classification {
+    pub kind: ContentKind,
+    pub evidence: Evidence,
+}
+
+impl fmt::Display for Classification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            ContentKind::Markdown => "markdown",
+            ContentKind::WasmComponent => "wasm component",
+            ContentKind::WasmModule => "core wasm module",
+            ContentKind::Binary => "binary data",
+        };
+        let evidence = match self.evidence {
+            Evidence::MagicBytes => "magic bytes",
+            Evidence::ContentType => "content type",
+            Evidence::Default => "default",
+        };
+        write!(f, "{} by {}", kind, evidence)
+    }
+}
+
+/// Classify a response body, optionally using the server's `Content-Type` header.
+pub fn classify(content_type: Option<&str>, body: &[u8]) -> Classification {
+    if let Some(kind) = sniff_wasm(body) {
+        return Classification {
+            kind,
+            evidence: Evidence::MagicBytes,
+        };
+    }
+
+    let mime = content_type.map(|value| {
+        value
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase()
+    });
+    match mime.as_deref() {
+        // claims to be wasm but the header is missing or unknown
+        Some("application/wasm") => Classification {
+            kind: ContentKind::Binary,
+            evidence: Evidence::ContentType,
+        },
+        Some(mime) if mime.starts_with("text/") || is_text_mime(mime) => Classification {
+            kind: ContentKind::Markdown,
+            evidence: Evidence::ContentType,
+        },
+        _ => Classification {
+            kind: if std::str::from_utf8(body).is_ok() {
+                ContentKind::Markdown
+            } else {
+                ContentKind::Binary
+            },
+            evidence: Evidence::Default,
+        },
+    }
+}
+
+/// Look at the preamble of `body` and tell core modules and components apart.
+pub fn sniff_wasm(body: &[u8]) -> Option<ContentKind> {
+    if body.len() < 8 || body[0..4] != WASM_MAGIC {
+        return None;
+    }
+    match u16::from_le_bytes([body[6], body[7]]) {
+        CORE_LAYER => Some(ContentKind::WasmModule),
+        COMPONENT_LAYER => Some(ContentKind::WasmComponent),
+        _ => None,
+    }
+}
+
+fn is_text_mime(mime: &str) -> bool {
+    matches!(
+        mime,
+        "application/json" | "application/markdown" | "application/x-markdown"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CORE: &[u8] = b"\0asm\x01\x00\x00\x00";
+    const COMPONENT: &[u8] = b"\0asm\x0d\x00\x01\x00";
+
+    fn kind(content_type: Option<&str>, body: &[u8]) -> (ContentKind, Evidence) {
+        let classification = classify(content_type, body);
+        (classification.kind, classification.evidence)
+    }
+
+    #[test]
+    fn magic_bytes_win_over_the_header() {
+        assert_eq!(
+            kind(Some("text/plain"), COMPONENT),
+            (ContentKind::WasmComponent, Evidence::MagicBytes)
+        );
+        assert_eq!(kind(None, CORE), (ContentKind::WasmModule, Evidence::MagicBytes));
+    }
+
+    #[test]
+    fn unknown_layer_is_not_wasm() {
+        assert_eq!(sniff_wasm(b"\0asm\x01\x00\x02\x00"), None);
+        assert_eq!(sniff_wasm(b"\0asm"), None);
+    }
+
+    #[test]
+    fn wasm_header_without_wasm_body_is_binary() {
+        assert_eq!(
+            kind(Some("application/wasm"), b"not wasm"),
+            (ContentKind::Binary, Evidence::ContentType)
+        );
+    }
+
+    #[test]
+    fn text_types_are_markdown() {
+        assert_eq!(
+            kind(Some("Text/Markdown; charset=utf-8"), b"# hi"),
+            (ContentKind::Markdown, Evidence::ContentType)
+        );
+        assert_eq!(
+            kind(Some("application/json"), b"{}"),
+            (ContentKind::Markdown, Evidence::ContentType)
+        );
+    }
+
+    #[test]
+    fn without_a_usable_header_utf8_decides() {
+        assert_eq!(kind(None, b"# hi"), (ContentKind::Markdown, Evidence::Default));
+        assert_eq!(
+            kind(Some("application/octet-stream"), &[0xff, 0xfe, 0x00]),
+            (ContentKind::Binary, Evidence::Default)
+        );
+    }
+}
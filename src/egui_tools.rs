@@ -1,5 +1,5 @@
-use egui::Context;
-use egui_wgpu::wgpu::{CommandEncoder, Device, Queue, StoreOp, TextureFormat, TextureView};
+use egui::{ClippedPrimitive, Context, TexturesDelta};
+use egui_wgpu::wgpu::{CommandEncoder, Device, Queue, StoreOp, TextureView};
 use egui_wgpu::{wgpu, Renderer, ScreenDescriptor};
 use egui_winit::State;
 use winit::event::WindowEvent;
@@ -9,7 +9,6 @@ use egui_material_icons;
 
 pub struct EguiRenderer {
     state: State,
-    renderer: Renderer,
     frame_started: bool,
 }
 
@@ -18,13 +17,8 @@ impl EguiRenderer {
         self.state.egui_ctx()
     }
 
-    pub fn new(
-        device: &Device,
-        output_color_format: TextureFormat,
-        output_depth_format: Option<TextureFormat>,
-        msaa_samples: u32,
-        window: &Window,
-    ) -> EguiRenderer {
+    /// egui for `window`, painted with `draw_with_wgpu` or a `SoftwarePainter`.
+    pub fn new(window: &Window) -> EguiRenderer {
         let egui_context = Context::default();
 
         egui_material_icons::initialize(&egui_context);
@@ -37,17 +31,8 @@ impl EguiRenderer {
             None,
             Some(2 * 1024), // default dimension is 2048
         );
-        let egui_renderer = Renderer::new(
-            device,
-            output_color_format,
-            output_depth_format,
-            msaa_samples,
-            true,
-        );
-
         EguiRenderer {
             state: egui_state,
-            renderer: egui_renderer,
             frame_started: false,
         }
     }
@@ -56,6 +41,14 @@ impl EguiRenderer {
         let _ = self.state.on_window_event(window, event);
     }
 
+    pub fn clipboard_text(&mut self) -> Option<String> {
+        self.state.clipboard_text()
+    }
+
+    pub fn set_clipboard_text(&mut self, text: String) {
+        self.state.set_clipboard_text(text);
+    }
+
     pub fn ppp(&mut self, v: f32) {
         self.context().set_pixels_per_point(v);
     }
@@ -66,20 +59,17 @@ impl EguiRenderer {
         self.frame_started = true;
     }
 
-    pub fn end_frame_and_draw(
+    /// Finish the frame begun with `begin_frame`, returning what is left to paint.
+    pub fn end_frame(
         &mut self,
-        device: &Device,
-        queue: &Queue,
-        encoder: &mut CommandEncoder,
         window: &Window,
-        window_surface_view: &TextureView,
-        screen_descriptor: ScreenDescriptor,
-    ) {
+        pixels_per_point: f32,
+    ) -> (Vec<ClippedPrimitive>, TexturesDelta) {
         if !self.frame_started {
-            panic!("begin_frame must be called before end_frame_and_draw can be called!");
+            panic!("begin_frame must be called before end_frame can be called!");
         }
 
-        self.ppp(screen_descriptor.pixels_per_point);
+        self.ppp(pixels_per_point);
 
         let full_output = self.state.egui_ctx().end_pass();
 
@@ -90,33 +80,37 @@ impl EguiRenderer {
             .state
             .egui_ctx()
             .tessellate(full_output.shapes, self.state.egui_ctx().pixels_per_point());
-        for (id, image_delta) in &full_output.textures_delta.set {
-            self.renderer
-                .update_texture(device, queue, *id, image_delta);
-        }
-        self.renderer
-            .update_buffers(device, queue, encoder, &tris, &screen_descriptor);
-        let rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: window_surface_view,
-                resolve_target: None,
-                ops: egui_wgpu::wgpu::Operations {
-                    load: egui_wgpu::wgpu::LoadOp::Load,
-                    store: StoreOp::Store,
-                },
-            })],
-            depth_stencil_attachment: None,
-            timestamp_writes: None,
-            label: Some("egui main render pass"),
-            occlusion_query_set: None,
-        });
-
-        self.renderer
-            .render(&mut rpass.forget_lifetime(), &tris, &screen_descriptor);
-        for x in &full_output.textures_delta.free {
-            self.renderer.free_texture(x)
-        }
 
         self.frame_started = false;
+        (tris, full_output.textures_delta)
     }
 }
+
+/// Paint a frame from `EguiRenderer::end_frame` into `window_surface_view`.
+pub fn draw_with_wgpu(
+    renderer: &mut Renderer,
+    device: &Device,
+    queue: &Queue,
+    encoder: &mut CommandEncoder,
+    window_surface_view: &TextureView,
+    tris: &[ClippedPrimitive],
+    screen_descriptor: &ScreenDescriptor,
+) {
+    renderer.update_buffers(device, queue, encoder, tris, screen_descriptor);
+    let rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+            view: window_surface_view,
+            resolve_target: None,
+            ops: egui_wgpu::wgpu::Operations {
+                load: egui_wgpu::wgpu::LoadOp::Load,
+                store: StoreOp::Store,
+            },
+        })],
+        depth_stencil_attachment: None,
+        timestamp_writes: None,
+        label: Some("egui main render pass"),
+        occlusion_query_set: None,
+    });
+
+    renderer.render(&mut rpass.forget_lifetime(), tris, screen_descriptor);
+}
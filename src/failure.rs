@@ -0,0 +1,134 @@
+//! Why a wasm page failed, in a form that can be shown to the user.
+//!
+//! `WasmFailure` pulls the useful parts out of a wasmtime error: the trap code, the wasm
+//! backtrace with DWARF symbols when the component carries debug info, and for link errors the
+//! imports the linker has no definition for. `to_markdown` renders it for the central panel.
+
+use std::fmt;
+
+use wasmtime::{Trap, WasmBacktrace};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailureKind {
+    /// The component could not be read or compiled.
+    Load,
+    /// The component imports something the host does not provide.
+    Link,
+    /// Instantiation failed for another reason, e.g. a start function trapped.
+    Instantiate,
+    /// The guest trapped while running.
+    Trap,
+    /// The component draws with WebGPU only and there is no graphics adapter.
+    NoGpu,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WasmFailure {
+    pub kind: FailureKind,
+    pub message: String,
+    pub trap_code: Option<String>,
+    pub backtrace: Vec<String>,
+    pub missing_imports: Vec<String>,
+}
+
+impl WasmFailure {
+    pub fn new(kind: FailureKind, error: &anyhow::Error) -> Self {
+        let backtrace = error
+            .downcast_ref::<WasmBacktrace>()
+            .map(format_backtrace)
+            .unwrap_or_default();
+        Self {
+            kind,
+            message: format!("{:#}", error),
+            trap_code: error.downcast_ref::<Trap>().map(|trap| trap.to_string()),
+            backtrace,
+            missing_imports: Vec::new(),
+        }
+    }
+
+    /// The linker could not satisfy the imports of a component or module, see `Runtime::prepare`.
+    pub fn link(error: &anyhow::Error, missing_imports: Vec<String>) -> Self {
+        Self {
+            missing_imports,
+            ..Self::new(FailureKind::Link, error)
+        }
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self.kind {
+            FailureKind::Load => "This page could not be loaded",
+            FailureKind::Link => "This page needs features the browser does not provide",
+            FailureKind::Instantiate => "This page failed to start",
+            FailureKind::Trap => "This page crashed",
+            FailureKind::NoGpu => "This page needs a graphics adapter",
+        }
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut page = String::new();
+        if let Some(code) = &self.trap_code {
+            page.push_str(&format!("**Trap:** {}\n\n", code));
+        }
+        if !self.missing_imports.is_empty() {
+            page.push_str("## Missing imports\n\n");
+            for import in &self.missing_imports {
+                page.push_str(&format!("- `{}`\n", import));
+            }
+            page.push('\n');
+        }
+        if !self.backtrace.is_empty() {
+            page.push_str("## Backtrace\n\n```\n");
+            for frame in &self.backtrace {
+                page.push_str(frame);
+                page.push('\n');
+            }
+            page.push_str("```\n\n");
+        }
+        page.push_str("## Error\n\n```\n");
+        page.push_str(&self.message);
+        page.push_str("\n```\n");
+        page
+    }
+}
+
+impl fmt::Display for WasmFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.trap_code {
+            Some(code) => write!(f, "{}", code),
+            None => write!(f, "{}", self.message.lines().next().unwrap_or("")),
+        }
+    }
+}
+
+fn format_backtrace(backtrace: &WasmBacktrace) -> Vec<String> {
+    let mut frames = Vec::new();
+    for (i, frame) in backtrace.frames().iter().enumerate() {
+        let module = frame.module().name().unwrap_or("<unknown>");
+        let symbols = frame.symbols();
+        if symbols.is_empty() {
+            let func = match frame.func_name() {
+                Some(name) => name.to_string(),
+                None => format!("<wasm function {}>", frame.func_index()),
+            };
+            let offset = frame
+                .module_offset()
+                .map(|offset| format!(" @ {:#x}", offset))
+                .unwrap_or_default();
+            frames.push(format!("{:>3}: {}!{}{}", i, module, func, offset));
+            continue;
+        }
+        // inlined functions show up as several symbols for one frame
+        for symbol in symbols {
+            let func = symbol.name().unwrap_or("<unknown>");
+            let location = match (symbol.file(), symbol.line()) {
+                (Some(file), Some(line)) => match symbol.column() {
+                    Some(column) => format!("\n       at {}:{}:{}", file, line, column),
+                    None => format!("\n       at {}:{}", file, line),
+                },
+                _ => String::new(),
+            };
+            frames.push(format!("{:>3}: {}!{}{}", i, module, func, location));
+        }
+    }
+    frames
+}
@@ -83,13 +83,12 @@ mod platform {
                 gc.get_or_insert_with(|| GraphicsContext::new(window)).create_surface(window);
 
             // Fill a buffer with a solid color.
-            //const DARK_GRAY: u32 = 0xff181818;
-            const CYAN: u32 = 0xff00FFFF;
+            const DARK_GRAY: u32 = 0xff181818;
 
             surface.resize(width, height).expect("Failed to resize the softbuffer surface");
 
             let mut buffer = surface.buffer_mut().expect("Failed to get the softbuffer buffer");
-            buffer.fill(CYAN);
+            buffer.fill(DARK_GRAY);
             buffer.present().expect("Failed to present the softbuffer buffer");
         })
     }
@@ -0,0 +1,76 @@
+//! Animation frames for wasm pages.
+//!
+//! The main window presents with vsync, so its redraws already run at the display's refresh
+//! rate. After each present the visible page gets a frame through `wasi:surface`, and the
+//! `FrameClock` records its number and timestamp for `m:browser/frame`. A page that is hidden,
+//! because the window is minimized or covered or the page's tab is not shown, gets no frames.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::host::frame::{self, FrameInfo};
+
+/// Frame length reported to pages that may not read the clock, 60 Hz.
+const NOMINAL_FRAME: Duration = Duration::from_nanos(16_666_667);
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameTime {
+    pub number: u64,
+    /// Since the page started.
+    pub timestamp: Duration,
+}
+
+/// Shared between the UI thread, which starts frames, and the page's host state, which
+/// reports them.
+#[derive(Clone)]
+pub struct FrameClock {
+    started: Instant,
+    current: Arc<Mutex<FrameTime>>,
+    precise: Arc<AtomicBool>,
+}
+
+impl FrameClock {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            current: Arc::new(Mutex::new(FrameTime::default())),
+            precise: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Start the next frame, `presented` being when the main window's frame went out.
+    pub fn tick(&self, presented: Instant) -> FrameTime {
+        let mut current = self.current.lock().unwrap();
+        current.number += 1;
+        current.timestamp = presented.saturating_duration_since(self.started);
+        *current
+    }
+
+    /// Whether the page may see real timestamps, which it may only with the clocks capability.
+    pub fn set_precise(&self, precise: bool) {
+        self.precise.store(precise, Ordering::Relaxed);
+    }
+
+    pub fn current(&self) -> FrameTime {
+        let current = *self.current.lock().unwrap();
+        if self.precise.load(Ordering::Relaxed) {
+            current
+        } else {
+            FrameTime {
+                number: current.number,
+                timestamp: NOMINAL_FRAME.mul_f64(current.number as f64),
+            }
+        }
+    }
+}
+
+impl frame::Host for FrameClock {
+    fn current_frame(&mut self) -> FrameInfo {
+        let current = self.current();
+        FrameInfo {
+            number: current.number,
+            timestamp: current.timestamp.as_secs_f64() * 1000.0,
+        }
+    }
+}
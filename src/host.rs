@@ -0,0 +1,9 @@
+//! Bindings for the `m:browser` interfaces in `wit/`, which the browser provides to pages
+//! next to WASI and wasi-gfx.
+
+wasmtime::component::bindgen!({
+    path: "wit",
+    world: "m:browser/page",
+});
+
+pub use m::browser::{chrome, clipboard, frame, input, storage, text_input, view};
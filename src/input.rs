@@ -0,0 +1,139 @@
+//! Extended input for wasm pages.
+//!
+//! `WinitEventToSurfaceProxy` sends what `wasi:surface` understands straight to the guest and
+//! pushes everything else onto the page's `InputQueue`. The guest drains the queue through
+//! `m:browser/input`, usually once per animation frame.
+//!
+//! Typed text goes to the page's `TextInput` instead, which the queue carries along with its
+//! `View`. The queue also tracks `UserActivation`: presses that reach the page, which host
+//! features such as reading the clipboard require, like transient activation on the web.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use winit::event::{MouseButton, TouchPhase};
+use winit::keyboard::ModifiersState;
+
+use crate::host::input::{self, Event, Modifiers, PointerButton};
+use crate::text_input::TextInput;
+use crate::view::View;
+
+/// Events kept for a page that never drains its queue. The oldest are dropped first.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// How long after a press the page counts as activated.
+const ACTIVATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Default)]
+pub struct InputQueue {
+    events: Arc<Mutex<VecDeque<Event>>>,
+    activation: UserActivation,
+    text_input: TextInput,
+    view: View,
+}
+
+impl InputQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, event: Event) {
+        let mut events = self.events.lock().unwrap();
+        if events.len() == QUEUE_CAPACITY {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    pub fn take(&self) -> Vec<Event> {
+        self.events.lock().unwrap().drain(..).collect()
+    }
+
+    pub fn activation(&self) -> UserActivation {
+        self.activation.clone()
+    }
+
+    pub fn text_input(&self) -> TextInput {
+        self.text_input.clone()
+    }
+
+    pub fn view(&self) -> View {
+        self.view.clone()
+    }
+}
+
+/// Whether the user is interacting with a page: when a key, button or touch last went down in
+/// it and whether it has keyboard focus.
+#[derive(Clone, Default)]
+pub struct UserActivation {
+    last: Arc<Mutex<Option<Instant>>>,
+    focused: Arc<AtomicBool>,
+}
+
+impl UserActivation {
+    /// A press reached the page.
+    pub fn activate(&self) {
+        *self.last.lock().unwrap() = Some(Instant::now());
+    }
+
+    pub fn set_focused(&self, focused: bool) {
+        self.focused.store(focused, Ordering::Relaxed);
+    }
+
+    pub fn focused(&self) -> bool {
+        self.focused.load(Ordering::Relaxed)
+    }
+
+    /// Whether the page was pressed in recently.
+    pub fn is_active(&self) -> bool {
+        self.last
+            .lock()
+            .unwrap()
+            .is_some_and(|last| last.elapsed() < ACTIVATION_TIMEOUT)
+    }
+
+    /// Like `is_active`, but using the activation up, so each press allows one use.
+    pub fn consume(&self) -> bool {
+        let mut last = self.last.lock().unwrap();
+        let active = last.is_some_and(|last| last.elapsed() < ACTIVATION_TIMEOUT);
+        *last = None;
+        active
+    }
+}
+
+impl input::Host for InputQueue {
+    fn take_events(&mut self) -> Vec<Event> {
+        self.take()
+    }
+}
+
+pub fn modifiers(state: ModifiersState) -> Modifiers {
+    Modifiers {
+        shift: state.shift_key(),
+        ctrl: state.control_key(),
+        alt: state.alt_key(),
+        meta: state.super_key(),
+    }
+}
+
+pub fn pointer_button(button: MouseButton) -> PointerButton {
+    match button {
+        MouseButton::Left => PointerButton::Primary,
+        MouseButton::Right => PointerButton::Secondary,
+        MouseButton::Middle => PointerButton::Auxiliary,
+        MouseButton::Back => PointerButton::Back,
+        MouseButton::Forward => PointerButton::Forward,
+        MouseButton::Other(_) => PointerButton::Other,
+    }
+}
+
+pub fn touch_phase(phase: TouchPhase) -> input::TouchPhase {
+    match phase {
+        TouchPhase::Started => input::TouchPhase::Start,
+        TouchPhase::Moved => input::TouchPhase::Move,
+        TouchPhase::Ended => input::TouchPhase::End,
+        TouchPhase::Cancelled => input::TouchPhase::Cancel,
+    }
+}
@@ -0,0 +1,288 @@
+//! Resource limits for wasm pages.
+//!
+//! Every page gets a `PageLimiter` installed on its store. Memory and table growth past the
+//! configured limits fails with a `LimitExceeded` error instead of a plain `false`, so the guest
+//! traps right away and the browser can tell the user which limit the page ran into. CPU time is
+//! measured by a `CpuBudget` and enforced by the epoch callback in `wasm.rs`.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use wasmtime::ResourceLimiter;
+
+#[derive(Clone, Copy, Debug)]
+pub struct PageLimits {
+    /// Largest size of any one linear memory, in bytes.
+    pub max_memory_bytes: usize,
+    /// Largest number of elements in any one table.
+    pub max_table_elements: usize,
+    /// Core instances a component may create, including the ones inside the component itself.
+    pub max_instances: usize,
+    /// Tables and linear memories a page may create. The pooling allocator reserves this many
+    /// for every page it can run at once.
+    pub max_tables: usize,
+    pub max_memories: usize,
+    /// How long the guest may run without picking up a new animation frame, roughly the work
+    /// it does for a single frame.
+    pub frame_cpu_budget: Duration,
+    /// How long a page without graphics, which never takes animation frames, may run in all.
+    pub run_cpu_budget: Duration,
+}
+
+impl Default for PageLimits {
+    fn default() -> Self {
+        Self {
+            max_memory_bytes: 1024 * 1024 * 1024,
+            max_table_elements: 100_000,
+            max_instances: 100,
+            max_tables: 16,
+            max_memories: 4,
+            frame_cpu_budget: Duration::from_secs(1),
+            run_cpu_budget: Duration::from_secs(60),
+        }
+    }
+}
+
+impl PageLimits {
+    /// Fail if a page with `memories` linear memories and `tables` tables could never run. Checked
+    /// before instantiating, since wasmtime reports counts over the limiter's maximum as a plain
+    /// error.
+    pub fn check_counts(&self, memories: usize, tables: usize) -> Result<(), LimitExceeded> {
+        if memories > self.max_memories {
+            return Err(LimitExceeded(format!(
+                "memory limit exceeded: the page needs {} memories, the limit is {}",
+                memories, self.max_memories
+            )));
+        }
+        if tables > self.max_tables {
+            return Err(LimitExceeded(format!(
+                "table limit exceeded: the page needs {} tables, the limit is {}",
+                tables, self.max_tables
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// The error a page is stopped with when it goes over one of its limits.
+#[derive(Clone, Debug)]
+pub struct LimitExceeded(pub String);
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Budget {
+    /// Time between animation frames.
+    PerFrame(Duration),
+    /// Time for the whole run, for pages that take no frames.
+    WholeRun(Duration),
+}
+
+struct Busy {
+    since: Instant,
+    // the latest animation frame the guest picked up
+    frame: u64,
+    budget: Budget,
+}
+
+/// How long a guest has been running since it last picked up an animation frame.
+///
+/// A guest learns about frames through host calls, so the budget starts over when a host call
+/// returns after a new frame was started, and after the page was paused. A page that does a lot
+/// of work in every frame keeps going as long as it keeps taking frames. A page without
+/// graphics never takes one, so it gets a budget for its whole run instead, see `whole_run`.
+///
+/// The clock only means something once the guest runs, so `restart` it right before.
+#[derive(Clone)]
+pub struct CpuBudget {
+    busy: Arc<Mutex<Busy>>,
+}
+
+impl CpuBudget {
+    pub fn new(per_frame: Duration) -> Self {
+        Self {
+            busy: Arc::new(Mutex::new(Busy {
+                since: Instant::now(),
+                frame: 0,
+                budget: Budget::PerFrame(per_frame),
+            })),
+        }
+    }
+
+    /// Count `budget` for the whole run instead of per animation frame.
+    pub fn whole_run(&self, budget: Duration) {
+        self.busy.lock().unwrap().budget = Budget::WholeRun(budget);
+    }
+
+    pub fn restart(&self) {
+        self.busy.lock().unwrap().since = Instant::now();
+    }
+
+    /// The page was paused for `paused`, which does not count against it.
+    pub fn resumed(&self, paused: Duration) {
+        let mut busy = self.busy.lock().unwrap();
+        match busy.budget {
+            Budget::PerFrame(_) => busy.since = Instant::now(),
+            Budget::WholeRun(_) => busy.since += paused,
+        }
+    }
+
+    /// A host call returned to the guest while `frame` was the current animation frame.
+    pub fn returned_from_host(&self, frame: u64) {
+        let mut busy = self.busy.lock().unwrap();
+        if frame > busy.frame {
+            busy.frame = frame;
+            if let Budget::PerFrame(_) = busy.budget {
+                busy.since = Instant::now();
+            }
+        }
+    }
+
+    /// Fail if the guest has been running for longer than the budget.
+    pub fn check(&self) -> Result<(), LimitExceeded> {
+        let busy = self.busy.lock().unwrap();
+        match busy.budget {
+            Budget::PerFrame(budget) if busy.since.elapsed() > budget => {
+                Err(LimitExceeded(format!(
+                    "CPU limit exceeded: the page ran for more than {} ms without taking an \
+                     animation frame",
+                    budget.as_millis()
+                )))
+            }
+            Budget::WholeRun(budget) if busy.since.elapsed() > budget => {
+                Err(LimitExceeded(format!(
+                    "CPU limit exceeded: the page ran for more than {} s",
+                    budget.as_secs()
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct PageLimiter {
+    limits: PageLimits,
+}
+
+impl PageLimiter {
+    pub fn new(limits: PageLimits) -> Self {
+        Self { limits }
+    }
+}
+
+impl ResourceLimiter for PageLimiter {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        if desired > self.limits.max_memory_bytes {
+            return Err(LimitExceeded(format!(
+                "memory limit exceeded: the page asked for {} MiB, the limit is {} MiB",
+                desired / (1024 * 1024),
+                self.limits.max_memory_bytes / (1024 * 1024)
+            ))
+            .into());
+        }
+        Ok(true)
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        if desired > self.limits.max_table_elements {
+            return Err(LimitExceeded(format!(
+                "table limit exceeded: the page asked for {} elements, the limit is {}",
+                desired, self.limits.max_table_elements
+            ))
+            .into());
+        }
+        Ok(true)
+    }
+
+    fn instances(&self) -> usize {
+        self.limits.max_instances
+    }
+
+    fn tables(&self) -> usize {
+        self.limits.max_tables
+    }
+
+    fn memories(&self) -> usize {
+        self.limits.max_memories
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_frames_restart_the_budget() {
+        let budget = CpuBudget::new(Duration::from_millis(20));
+        std::thread::sleep(Duration::from_millis(15));
+        budget.returned_from_host(1);
+        std::thread::sleep(Duration::from_millis(15));
+        assert!(budget.check().is_ok());
+    }
+
+    #[test]
+    fn host_calls_without_a_new_frame_do_not() {
+        let budget = CpuBudget::new(Duration::from_millis(20));
+        budget.returned_from_host(1);
+        std::thread::sleep(Duration::from_millis(15));
+        budget.returned_from_host(1);
+        std::thread::sleep(Duration::from_millis(15));
+        assert!(budget.check().is_err());
+    }
+
+    #[test]
+    fn memory_and_table_counts_are_checked_against_the_limits() {
+        let limits = PageLimits::default();
+        assert!(limits.check_counts(limits.max_memories, limits.max_tables).is_ok());
+        assert!(limits.check_counts(limits.max_memories + 1, 0).is_err());
+        assert!(limits.check_counts(0, limits.max_tables + 1).is_err());
+    }
+
+    #[test]
+    fn time_before_the_guest_runs_does_not_count() {
+        // compiling and a permission prompt the user takes a while to answer
+        let budget = CpuBudget::new(Duration::from_millis(20));
+        std::thread::sleep(Duration::from_millis(30));
+        budget.restart();
+        assert!(budget.check().is_ok());
+    }
+
+    #[test]
+    fn pages_without_frames_get_a_budget_for_the_whole_run() {
+        let budget = CpuBudget::new(Duration::from_millis(10));
+        budget.whole_run(Duration::from_millis(40));
+        budget.restart();
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(budget.check().is_ok());
+        budget.returned_from_host(1);
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(budget.check().is_err());
+    }
+
+    #[test]
+    fn paused_time_does_not_count_against_the_whole_run() {
+        let budget = CpuBudget::new(Duration::from_millis(10));
+        budget.whole_run(Duration::from_millis(40));
+        budget.restart();
+        std::thread::sleep(Duration::from_millis(30));
+        budget.resumed(Duration::from_millis(30));
+        assert!(budget.check().is_ok());
+    }
+}
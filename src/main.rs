@@ -1,9 +1,28 @@
 mod app;
+mod cache;
+mod chrome;
+mod clipboard;
+mod console;
+mod content;
 mod egui_tools;
+mod failure;
+mod frames;
+mod host;
+mod input;
+mod limits;
+mod navigation;
+mod network;
+mod page;
+mod permissions;
+mod software;
+mod storage;
+mod text_input;
+mod view;
 mod wasm;
 mod winit_wasi;
 
 use winit::event_loop::{ControlFlow, EventLoop};
+use winit_wasi::{MainThreadAction, WasiWinitEventLoopProxy};
 
 fn main() {
     #[cfg(not(target_arch = "wasm32"))]
@@ -13,11 +32,16 @@ fn main() {
 }
 
 async fn run() {
-    let event_loop = EventLoop::new().unwrap();
+    // the browser's own messages, pages print to their tab's console instead
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("m_browser=info"))
+        .init();
+
+    let event_loop = EventLoop::<MainThreadAction>::with_user_event().build().unwrap();
 
     event_loop.set_control_flow(ControlFlow::Poll);
 
-    let mut app = app::App::new();
+    let main_thread = WasiWinitEventLoopProxy::new(event_loop.create_proxy());
+    let mut app = app::App::new(main_thread);
 
     event_loop.run_app(&mut app).expect("Failed to run app");
 }
@@ -0,0 +1,320 @@
+//! Background page loading.
+//!
+//! Fetching a page can take as long as the server wants it to, so every navigation runs on its
+//! own worker thread. Workers report back through a channel that `App` drains once per frame,
+//! which keeps the egui window and any running wasm page responsive while a slow host answers.
+//! A wasm page whose server cannot be reached is loaded from the component cache instead, and
+//! reported as an offline copy. A server that answers with an error status gets its error shown.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::mpsc;
+
+use crate::cache::ComponentCache;
+use crate::content::{classify, Classification, ContentKind};
+
+/// What a finished navigation does to the tab's history.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistoryAction {
+    /// A new location: the current one moves onto the back stack and forward is cleared.
+    Push,
+    /// Step to the top of the back stack.
+    Back,
+    /// Step to the top of the forward stack.
+    Forward,
+    /// Load the tab's current location again without touching history.
+    Reload,
+}
+
+#[derive(Clone, Debug)]
+pub struct NavigationRequest {
+    pub id: u64,
+    pub tab: i32,
+    pub location: String,
+    pub action: HistoryAction,
+}
+
+#[derive(Clone, Debug)]
+pub enum PageContents {
+    /// Markdown source to render in the central panel.
+    Markdown(String),
+    /// Path of the downloaded wasm component in the component cache.
+    Wasm(PathBuf),
+}
+
+/// Where the contents of a loaded page came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    Network,
+    /// The server could not be reached, for the given reason, so this is the copy cached when
+    /// the page last loaded.
+    OfflineCopy(String),
+}
+
+#[derive(Debug)]
+pub enum NavigationEvent {
+    Loading(NavigationRequest),
+    Loaded(NavigationRequest, PageContents, Classification, Source),
+    Failed(NavigationRequest, String),
+}
+
+pub struct Navigator {
+    next_id: u64,
+    // latest request issued for each tab, anything older is stale
+    latest: HashMap<i32, u64>,
+    sender: mpsc::Sender<NavigationEvent>,
+    receiver: mpsc::Receiver<NavigationEvent>,
+    cache: Option<ComponentCache>,
+}
+
+impl Navigator {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let cache = match ComponentCache::open_default() {
+            Ok(cache) => Some(cache),
+            Err(e) => {
+                log::warn!("Failed to open component cache: {}", e);
+                None
+            }
+        };
+        Self {
+            next_id: 0,
+            latest: HashMap::new(),
+            sender,
+            receiver,
+            cache,
+        }
+    }
+
+    /// Start loading `location` for `tab` on a worker thread.
+    ///
+    /// A newer request for the same tab supersedes this one, and its results are dropped.
+    pub fn start(&mut self, tab: i32, location: String, action: HistoryAction) {
+        self.next_id += 1;
+        let request = NavigationRequest {
+            id: self.next_id,
+            tab,
+            location,
+            action,
+        };
+        self.latest.insert(tab, request.id);
+
+        let sender = self.sender.clone();
+        let cache = self.cache.clone();
+        let _ = sender.send(NavigationEvent::Loading(request.clone()));
+        std::thread::spawn(move || {
+            let event = match navigate(request.location.clone(), cache.as_ref()) {
+                Ok((contents, classification, source)) => {
+                    NavigationEvent::Loaded(request, contents, classification, source)
+                }
+                Err(e) => NavigationEvent::Failed(request, e),
+            };
+            // the receiver is gone once the app has shut down
+            let _ = sender.send(event);
+        });
+    }
+
+    /// Drain the events that arrived since the last call, skipping superseded requests.
+    pub fn poll(&mut self) -> Vec<NavigationEvent> {
+        let mut events = Vec::new();
+        while let Ok(event) = self.receiver.try_recv() {
+            let request = match &event {
+                NavigationEvent::Loading(request)
+                | NavigationEvent::Loaded(request, ..)
+                | NavigationEvent::Failed(request, _) => request,
+            };
+            if self.latest.get(&request.tab) != Some(&request.id) {
+                continue;
+            }
+            if !matches!(event, NavigationEvent::Loading(_)) {
+                self.latest.remove(&request.tab);
+            }
+            events.push(event);
+        }
+        events
+    }
+}
+
+pub fn navigate(
+    location: String,
+    cache: Option<&ComponentCache>,
+) -> Result<(PageContents, Classification, Source), String> {
+    log::debug!("Navigating to URL: {}", location);
+    let response = match reqwest::blocking::get(location.clone()) {
+        Ok(response) => response,
+        // a wasm page that was loaded before still runs while its server is unreachable, but a
+        // server that answers decides what the page is
+        Err(e) if e.is_connect() || e.is_timeout() => {
+            return cached_copy(&location, cache, &e).ok_or_else(|| e.to_string());
+        }
+        Err(e) => return Err(e.to_string()),
+    };
+    let response = response.error_for_status().map_err(|e| e.to_string())?;
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let body = response.bytes().map_err(|e| e.to_string())?;
+
+    let classification = classify(content_type.as_deref(), &body);
+    log::info!("Classified as {}", classification);
+    let contents = match classification.kind {
+        ContentKind::Markdown => PageContents::Markdown(String::from_utf8_lossy(&body).into_owned()),
+        // core modules run through WASI preview1, see `Runtime`
+        ContentKind::WasmComponent | ContentKind::WasmModule => {
+            let cache = cache.ok_or("component cache is unavailable")?;
+            let path = cache
+                .insert(&location, &body)
+                .map_err(|e| format!("Failed to cache wasm page: {}", e))?;
+            PageContents::Wasm(path)
+        }
+        ContentKind::Binary => return Err(format!("cannot display {}", classification)),
+    };
+    Ok((contents, classification, Source::Network))
+}
+
+/// The wasm page `location` served the last time it was loaded, if it is still cached.
+fn cached_copy(
+    location: &str,
+    cache: Option<&ComponentCache>,
+    error: &reqwest::Error,
+) -> Option<(PageContents, Classification, Source)> {
+    let path = cache?.lookup(location)?;
+    let mut preamble = [0; 8];
+    File::open(&path)
+        .and_then(|mut file| file.read_exact(&mut preamble))
+        .ok()?;
+    log::info!("{} is unreachable, using the cached copy: {}", location, error);
+    let source = Source::OfflineCopy(error.to_string());
+    Some((PageContents::Wasm(path), classify(None, &preamble), source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    fn navigator() -> Navigator {
+        let (sender, receiver) = mpsc::channel();
+        Navigator {
+            next_id: 0,
+            latest: HashMap::new(),
+            sender,
+            receiver,
+            cache: None,
+        }
+    }
+
+    fn request(navigator: &mut Navigator, tab: i32) -> NavigationRequest {
+        navigator.next_id += 1;
+        let request = NavigationRequest {
+            id: navigator.next_id,
+            tab,
+            location: format!("https://example.com/{}", navigator.next_id),
+            action: HistoryAction::Push,
+        };
+        navigator.latest.insert(tab, request.id);
+        request
+    }
+
+    fn loaded(request: NavigationRequest) -> NavigationEvent {
+        let classification = classify(Some("text/markdown"), b"# hi");
+        let contents = PageContents::Markdown("# hi".into());
+        NavigationEvent::Loaded(request, contents, classification, Source::Network)
+    }
+
+    /// A fresh component cache in its own temporary directory.
+    fn scratch_cache(name: &str) -> ComponentCache {
+        let root = std::env::temp_dir()
+            .join(format!("m-browser-navigation-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&root);
+        ComponentCache::open(root, 1024 * 1024).unwrap()
+    }
+
+    /// A server on a free local port that answers every request with `404 Not Found`.
+    fn missing_page_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let origin = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request);
+                let _ = stream.write_all(
+                    b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                );
+            }
+        });
+        origin
+    }
+
+    const MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+    #[test]
+    fn error_statuses_are_errors_even_with_a_cached_copy() {
+        let cache = scratch_cache("404");
+        let location = format!("{}/page.wasm", missing_page_server());
+        cache.insert(&location, MODULE).unwrap();
+
+        let error = navigate(location, Some(&cache)).err().unwrap();
+        assert!(error.contains("404"), "{}", error);
+    }
+
+    #[test]
+    fn unreachable_servers_fall_back_to_an_offline_copy() {
+        let cache = scratch_cache("offline");
+        // nothing listens on port 1
+        let location = "http://127.0.0.1:1/page.wasm".to_string();
+        assert!(navigate(location.clone(), Some(&cache)).is_err());
+
+        let path = cache.insert(&location, MODULE).unwrap();
+        let (contents, _, source) = navigate(location, Some(&cache)).unwrap();
+        assert!(matches!(contents, PageContents::Wasm(cached) if cached == path));
+        assert!(matches!(source, Source::OfflineCopy(_)));
+    }
+
+    #[test]
+    fn poll_drops_superseded_requests() {
+        let mut navigator = navigator();
+        let first = request(&mut navigator, 0);
+        let second = request(&mut navigator, 0);
+        navigator.sender.send(loaded(first)).unwrap();
+        navigator.sender.send(loaded(second.clone())).unwrap();
+
+        let events = navigator.poll();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], NavigationEvent::Loaded(r, ..) if r.id == second.id));
+    }
+
+    #[test]
+    fn poll_keeps_tabs_apart() {
+        let mut navigator = navigator();
+        let a = request(&mut navigator, 0);
+        let b = request(&mut navigator, 1);
+        navigator.sender.send(loaded(b)).unwrap();
+        navigator.sender.send(NavigationEvent::Failed(a, "timeout".into())).unwrap();
+
+        assert_eq!(navigator.poll().len(), 2);
+    }
+
+    #[test]
+    fn poll_drops_events_after_the_request_finished() {
+        let mut navigator = navigator();
+        let request = request(&mut navigator, 0);
+        navigator.sender.send(NavigationEvent::Loading(request.clone())).unwrap();
+        navigator.sender.send(loaded(request.clone())).unwrap();
+        navigator.sender.send(loaded(request)).unwrap();
+
+        let events = navigator.poll();
+        assert_eq!(events.len(), 2);
+        assert!(matches!(events[0], NavigationEvent::Loading(_)));
+        assert!(matches!(events[1], NavigationEvent::Loaded(..)));
+    }
+}
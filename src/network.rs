@@ -0,0 +1,344 @@
+//! Outgoing HTTP for wasm pages, `wasi:http/outgoing-handler`.
+//!
+//! A page may fetch from its own origin. Requests to any other origin are refused with
+//! `HTTP-request-denied` and listed in the tab's network panel, where the user can allow that
+//! origin for the rest of the page's run. Every request shows up there with its outcome.
+//!
+//! A page loaded from a local server, such as `http://localhost:8080`, talks to that server as
+//! its own origin, so a local stand-in can take the place of a real backend.
+
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use wasmtime_wasi_http::bindings::http::types::ErrorCode;
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+use wasmtime_wasi_http::types::{
+    default_send_request_handler, HostFutureIncomingResponse, OutgoingRequestConfig,
+};
+use wasmtime_wasi_http::HttpResult;
+
+use crate::permissions::origin_of;
+
+/// Requests kept in the panel. The oldest are dropped first.
+const MAX_ENTRIES: usize = 1000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RequestState {
+    /// Refused, the origin is not the page's own and was not allowed.
+    Blocked,
+    Pending,
+    /// Answered with this status code.
+    Done(u16),
+    Failed(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct NetworkEntry {
+    id: u64,
+    /// Time since the page was started.
+    pub time: Duration,
+    pub method: String,
+    pub url: String,
+    pub origin: String,
+    pub state: RequestState,
+}
+
+#[derive(Default)]
+struct Log {
+    next_id: u64,
+    entries: VecDeque<NetworkEntry>,
+}
+
+/// The guest's end of the network panel, kept in its host state.
+#[derive(Clone)]
+pub struct PageNetwork {
+    origin: String,
+    started: Instant,
+    log: Arc<Mutex<Log>>,
+    // origins besides its own the user let this page reach
+    allowed: Arc<Mutex<BTreeSet<String>>>,
+}
+
+impl PageNetwork {
+    fn new(origin: &str) -> Self {
+        Self {
+            origin: origin.to_string(),
+            started: Instant::now(),
+            log: Arc::new(Mutex::new(Log::default())),
+            allowed: Arc::new(Mutex::new(BTreeSet::new())),
+        }
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        origin == self.origin || self.allowed.lock().unwrap().contains(origin)
+    }
+
+    /// Let the page reach `origin` for the rest of its run.
+    fn allow(&self, origin: String) {
+        self.allowed.lock().unwrap().insert(origin);
+    }
+
+    /// Log a request, returning its id if it may go out.
+    fn admit(&self, method: &str, url: &str) -> Option<u64> {
+        let origin = origin_of(url);
+        let allowed = self.allows(&origin);
+        let mut log = self.log.lock().unwrap();
+        let id = log.next_id;
+        log.next_id += 1;
+        if log.entries.len() == MAX_ENTRIES {
+            log.entries.pop_front();
+        }
+        log.entries.push_back(NetworkEntry {
+            id,
+            time: self.started.elapsed(),
+            method: method.to_string(),
+            url: url.to_string(),
+            origin,
+            state: if allowed {
+                RequestState::Pending
+            } else {
+                RequestState::Blocked
+            },
+        });
+        allowed.then_some(id)
+    }
+
+    fn finish(&self, id: u64, state: RequestState) {
+        let mut log = self.log.lock().unwrap();
+        if let Some(entry) = log.entries.iter_mut().find(|entry| entry.id == id) {
+            entry.state = state;
+        }
+    }
+
+    /// Send `request` if the policy lets it through, for `WasiHttpView::send_request`.
+    pub fn send_request(
+        &self,
+        request: hyper::Request<HyperOutgoingBody>,
+        config: OutgoingRequestConfig,
+    ) -> HttpResult<HostFutureIncomingResponse> {
+        let url = request.uri().to_string();
+        let Some(id) = self.admit(request.method().as_str(), &url) else {
+            log::debug!("Blocked request to {}", url);
+            return Err(ErrorCode::HttpRequestDenied.into());
+        };
+        let network = self.clone();
+        let handle = wasmtime_wasi::runtime::spawn(async move {
+            let response = default_send_request_handler(request, config).await;
+            let state = match &response {
+                Ok(response) => RequestState::Done(response.resp.status().as_u16()),
+                Err(e) => RequestState::Failed(format!("{:?}", e)),
+            };
+            network.finish(id, state);
+            Ok(response)
+        });
+        Ok(HostFutureIncomingResponse::pending(handle))
+    }
+}
+
+/// A tab's network panel, listing the requests of its current page.
+pub struct Network {
+    page: Option<PageNetwork>,
+    show_blocked_only: bool,
+}
+
+impl Network {
+    pub fn new() -> Self {
+        Self {
+            page: None,
+            show_blocked_only: false,
+        }
+    }
+
+    /// Start logging a new page from `origin`. Its allowlist starts out empty.
+    pub fn attach(&mut self, origin: &str) -> PageNetwork {
+        let page = PageNetwork::new(origin);
+        self.page = Some(page.clone());
+        page
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        let Some(page) = self.page.as_ref() else {
+            ui.label("No wasm page has run in this tab.");
+            return;
+        };
+        let mut allow = None;
+        ui.horizontal(|ui| {
+            ui.label(format!("Own origin: {}", page.origin));
+            ui.checkbox(&mut self.show_blocked_only, "Blocked only");
+            if ui.button("Clear").clicked() {
+                page.log.lock().unwrap().entries.clear();
+            }
+        });
+        ui.separator();
+
+        let error_color = ui.visuals().error_fg_color;
+        let entries = page.log.lock().unwrap().entries.clone();
+        egui::ScrollArea::vertical()
+            .auto_shrink([false, false])
+            .stick_to_bottom(true)
+            .show(ui, |ui| {
+                for entry in &entries {
+                    let blocked = entry.state == RequestState::Blocked;
+                    if self.show_blocked_only && !blocked {
+                        continue;
+                    }
+                    ui.horizontal(|ui| {
+                        let mut text = egui::RichText::new(format_entry(entry)).monospace();
+                        if blocked || matches!(entry.state, RequestState::Failed(_)) {
+                            text = text.color(error_color);
+                        }
+                        ui.label(text);
+                        if blocked
+                            && !page.allows(&entry.origin)
+                            && ui.small_button(format!("Allow {}", entry.origin)).clicked()
+                        {
+                            allow = Some(entry.origin.clone());
+                        }
+                    });
+                }
+            });
+        if let Some(origin) = allow {
+            page.allow(origin);
+        }
+    }
+}
+
+fn format_entry(entry: &NetworkEntry) -> String {
+    let state = match &entry.state {
+        RequestState::Blocked => "blocked".to_string(),
+        RequestState::Pending => "...".to_string(),
+        RequestState::Done(status) => status.to_string(),
+        RequestState::Failed(e) => format!("failed: {}", e),
+    };
+    format!(
+        "[{:>8.3}] {} {} {}",
+        entry.time.as_secs_f64(),
+        entry.method,
+        entry.url,
+        state
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    use http_body_util::{BodyExt, Empty};
+
+    /// A server on a free local port that answers every request with `204 No Content`.
+    fn local_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let origin = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request);
+                let _ = stream.write_all(b"HTTP/1.1 204 No Content\r\nconnection: close\r\n\r\n");
+            }
+        });
+        origin
+    }
+
+    fn send(page: &PageNetwork, url: &str) -> HttpResult<HostFutureIncomingResponse> {
+        let body = Empty::<hyper::body::Bytes>::new().map_err(|never| match never {}).boxed();
+        let request = hyper::Request::get(url).body(body).unwrap();
+        let config = OutgoingRequestConfig {
+            use_tls: false,
+            connect_timeout: Duration::from_secs(5),
+            first_byte_timeout: Duration::from_secs(5),
+            between_bytes_timeout: Duration::from_secs(5),
+        };
+        page.send_request(request, config)
+    }
+
+    fn entries(page: &PageNetwork) -> Vec<NetworkEntry> {
+        page.log.lock().unwrap().entries.iter().cloned().collect()
+    }
+
+    /// The state the latest request ends up in, waiting while it is pending.
+    fn settled(page: &PageNetwork) -> RequestState {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let state = entries(page).last().unwrap().state.clone();
+            if state != RequestState::Pending || Instant::now() > deadline {
+                return state;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn requests_to_the_own_origin_go_out() {
+        let origin = local_server();
+        let page = Network::new().attach(&origin);
+        // dropping the response aborts the request, so it is kept until it settled
+        let response = send(&page, &format!("{}/data", origin));
+        assert!(response.is_ok());
+        assert_eq!(settled(&page), RequestState::Done(204));
+        let entries = entries(&page);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].method, "GET");
+        assert_eq!(entries[0].origin, origin);
+    }
+
+    #[test]
+    fn requests_to_other_origins_are_blocked() {
+        let server = local_server();
+        let page = Network::new().attach("http://127.0.0.1:1");
+        let error = send(&page, &format!("{}/data", server)).err().unwrap();
+        assert!(matches!(error.downcast(), Ok(ErrorCode::HttpRequestDenied)));
+        let entries = entries(&page);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].state, RequestState::Blocked);
+        assert_eq!(entries[0].origin, server);
+    }
+
+    #[test]
+    fn allowed_origins_are_let_through() {
+        let server = local_server();
+        let page = Network::new().attach("http://127.0.0.1:1");
+        page.allow(server.clone());
+        let response = send(&page, &format!("{}/data", server));
+        assert!(response.is_ok());
+        assert_eq!(settled(&page), RequestState::Done(204));
+    }
+
+    #[test]
+    fn the_same_host_on_another_port_is_another_origin() {
+        let page = Network::new().attach("http://localhost:8080");
+        assert!(page.admit("GET", "http://localhost:8080/api").is_some());
+        assert!(page.admit("GET", "http://localhost:8081/api").is_none());
+        assert!(page.admit("GET", "https://localhost:8080/api").is_none());
+    }
+
+    #[test]
+    fn the_panel_keeps_the_latest_requests() {
+        let page = Network::new().attach("http://localhost:8080");
+        for _ in 0..=MAX_ENTRIES {
+            page.admit("GET", "http://localhost:8080/");
+        }
+        let entries = entries(&page);
+        assert_eq!(entries.len(), MAX_ENTRIES);
+        assert_eq!(entries[0].id, 1);
+    }
+
+    #[test]
+    fn a_new_page_starts_with_an_empty_panel_and_allowlist() {
+        let mut network = Network::new();
+        let first = network.attach("http://localhost:8080");
+        first.allow("http://example.com".to_string());
+        first.admit("GET", "http://example.com/");
+
+        let second = network.attach("http://localhost:8080");
+        assert!(!second.allows("http://example.com"));
+        assert!(entries(network.page.as_ref().unwrap()).is_empty());
+        assert_eq!(entries(&first).len(), 1);
+    }
+}
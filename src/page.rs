@@ -0,0 +1,375 @@
+//! A wasm page running in a tab.
+//!
+//! Each `Tab` that shows a wasm page owns a `WasmPage`: the running instance, the borderless
+//! window laid over the central panel and any further canvas windows, together with the
+//! proxies that turn winit events into input for the guest. Switching tabs hides the page's
+//! windows and leaves the instance to the `BackgroundPolicy`, switching back shows them again.
+//! A headless page, such as a core module, keeps its window hidden and the tab shows its
+//! console instead. A page that locks the pointer or goes fullscreen gives that up when it is
+//! hidden or loses focus, or when the user presses Escape.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use winit::dpi::{PhysicalPosition, PhysicalSize};
+use winit::event::{ElementState, KeyEvent, WindowEvent};
+use winit::keyboard::{Key, NamedKey};
+use winit::window::{CursorGrabMode, Window, WindowId};
+
+use crate::host::view::PointerMode;
+use crate::wasm::{ExitStatus, WasmInstance};
+use crate::winit_wasi::{EventSpace, WinitEventToSurfaceProxy};
+
+/// How often a page kept running in the background still gets an animation frame.
+const BACKGROUND_FRAME_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What happens to a wasm page while its tab is not shown.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BackgroundPolicy {
+    /// Keep running, with an animation frame about once a second.
+    KeepRunning,
+    /// Park the guest at its next epoch check until the tab is shown again.
+    #[default]
+    Suspend,
+    /// Stop the page, it is started again from the cached component when the tab is shown.
+    Stop,
+}
+
+pub struct WasmPage {
+    pub instance: WasmInstance,
+    window: Arc<Window>,
+    handler: WinitEventToSurfaceProxy,
+    // further canvases, each in a window of its own
+    canvas_windows: Vec<(Arc<Window>, WinitEventToSurfaceProxy)>,
+    // where the page's window was last placed, in physical pixels of the main window
+    area: Option<(PhysicalPosition<i32>, PhysicalSize<u32>)>,
+    // whether keyboard input goes to the page, set by clicking into or out of it
+    focused: bool,
+    // whether the window is hidden because egui draws over the panel, see `set_covered`
+    covered: bool,
+    last_background_frame: Option<Instant>,
+}
+
+impl WasmPage {
+    pub fn new(
+        instance: WasmInstance,
+        window: Arc<Window>,
+        handler: WinitEventToSurfaceProxy,
+        area: Option<(PhysicalPosition<i32>, PhysicalSize<u32>)>,
+    ) -> Self {
+        Self {
+            instance,
+            window,
+            handler,
+            canvas_windows: Vec::new(),
+            area,
+            focused: false,
+            covered: false,
+            last_background_frame: None,
+        }
+    }
+
+    pub fn window_id(&self) -> WindowId {
+        self.window.id()
+    }
+
+    pub fn window(&self) -> &Window {
+        &self.window
+    }
+
+    pub fn handler(&self) -> &WinitEventToSurfaceProxy {
+        &self.handler
+    }
+
+    pub fn add_canvas_window(&mut self, window: Arc<Window>, handler: WinitEventToSurfaceProxy) {
+        self.canvas_windows.push((window, handler));
+    }
+
+    pub fn canvas_count(&self) -> usize {
+        self.canvas_windows.len() + 1
+    }
+
+    /// Where the page's window is, in physical pixels of the main window, once it was placed.
+    pub fn area(&self) -> Option<(PhysicalPosition<i32>, PhysicalSize<u32>)> {
+        self.area
+    }
+
+    /// Whether the page has no graphics, see `WasmInstance::headless`.
+    pub fn headless(&self) -> bool {
+        self.instance.headless()
+    }
+
+    pub fn set_visible(&mut self, visible: bool) {
+        self.covered = false;
+        self.window.set_visible(visible && !self.headless());
+        for (window, _) in &self.canvas_windows {
+            window.set_visible(visible);
+        }
+        if !visible {
+            self.focused = false;
+            self.handler.set_focused(false);
+            self.handler.input().view().exit();
+            self.update_view();
+        }
+    }
+
+    /// Hide the page's window while an egui window, menu or popup reaches into it. The native
+    /// window is always drawn on top, so it would cover them otherwise.
+    pub fn set_covered(&mut self, covered: bool) {
+        if self.covered == covered {
+            return;
+        }
+        self.covered = covered;
+        self.window.set_visible(!covered && !self.headless());
+    }
+
+    /// Whether the page asked to be shown fullscreen.
+    pub fn fullscreen(&self) -> bool {
+        self.handler.input().view().fullscreen()
+    }
+
+    /// Put the page in the background according to `policy`. Returns `false` if the page
+    /// should be stopped instead.
+    pub fn hide(&mut self, policy: BackgroundPolicy) -> bool {
+        self.set_visible(false);
+        match policy {
+            BackgroundPolicy::KeepRunning => true,
+            BackgroundPolicy::Suspend => {
+                self.instance.pause();
+                true
+            }
+            BackgroundPolicy::Stop => false,
+        }
+    }
+
+    /// Bring a background page back, it is placed over the panel again with the next frame.
+    pub fn show(&mut self) {
+        self.instance.resume();
+        self.area = None;
+        self.last_background_frame = None;
+        self.set_visible(true);
+    }
+
+    /// Stop the guest and hide its windows.
+    pub fn stop(mut self) -> ExitStatus {
+        self.set_visible(false);
+        self.instance.terminate()
+    }
+
+    /// Keep the page's window exactly over the central panel, at `panel`.
+    ///
+    /// The pinned wasi-gfx runtime takes a native window from wasi-surface and creates the
+    /// swapchain for it itself, wasi-webgpu from the window handle on the runtime's wgpu-core
+    /// instance and wasi-frame-buffer through softbuffer. Nothing in between lets the host hand
+    /// the guest a texture to render into instead, so the page gets a borderless child window,
+    /// moved and resized along with the panel. Compositing into egui needs a graphics-context
+    /// backend that presents to a host texture, which those crates do not have yet.
+    ///
+    /// A page that asked for a particular canvas size gets that, in logical pixels, up to the
+    /// size of the panel.
+    pub fn place(&mut self, panel: (PhysicalPosition<i32>, PhysicalSize<u32>)) {
+        if self.headless() {
+            if self.window.is_visible() != Some(false) {
+                self.window.set_visible(false);
+            }
+            return;
+        }
+        let (position, panel) = panel;
+        let size = match self.instance.requested_canvas_size() {
+            Some((width, height)) => {
+                let scale_factor = self.window.scale_factor();
+                let scaled = |logical: Option<u32>, panel: u32| match logical {
+                    Some(logical) => {
+                        ((logical as f64 * scale_factor).round() as u32).clamp(1, panel)
+                    }
+                    None => panel,
+                };
+                PhysicalSize::new(scaled(width, panel.width), scaled(height, panel.height))
+            }
+            None => panel,
+        };
+        let area = (position, size);
+        if self.area == Some(area) {
+            return;
+        }
+        self.window.set_outer_position(position);
+        let _ = self.window.request_inner_size(size);
+        self.area = Some(area);
+        self.handler.set_area(position, size);
+    }
+
+    /// Pass `event` on to the page if it is meant for it.
+    ///
+    /// Pointer events count when they happen over the page, whether they arrive at the page's
+    /// window or at the main window underneath it. Keys only go to the page after it was
+    /// clicked, and never while an egui widget such as the URL bar has keyboard focus; the
+    /// page is told when that focus comes and goes.
+    pub fn route_input(
+        &mut self,
+        event: &WindowEvent,
+        space: EventSpace,
+        egui_wants_keyboard: bool,
+        main_window_focused: bool,
+    ) {
+        let from_page = space == EventSpace::Page;
+        if let WindowEvent::KeyboardInput {
+            event:
+                KeyEvent {
+                    logical_key: Key::Named(NamedKey::Escape),
+                    state: ElementState::Pressed,
+                    ..
+                },
+            ..
+        } = event
+        {
+            // Escape always gets the user out, the page only sees it if there was nothing to undo
+            if self.handler.input().view().exit() {
+                return;
+            }
+        }
+        if let WindowEvent::MouseInput {
+            state: ElementState::Pressed,
+            ..
+        } = event
+        {
+            self.focused = from_page || self.handler.pointer_inside();
+        }
+
+        match event {
+            WindowEvent::KeyboardInput { .. } | WindowEvent::Ime(_) => {
+                if self.focused && !egui_wants_keyboard {
+                    self.handler.send_event(event, space);
+                }
+            }
+            WindowEvent::Focused(focused) => {
+                if from_page {
+                    self.focused = *focused;
+                }
+            }
+            WindowEvent::CursorMoved { .. }
+            | WindowEvent::CursorEntered { .. }
+            | WindowEvent::CursorLeft { .. }
+            | WindowEvent::MouseInput { .. }
+            | WindowEvent::MouseWheel { .. }
+            | WindowEvent::Touch(_)
+            | WindowEvent::ModifiersChanged(_)
+            | WindowEvent::ScaleFactorChanged { .. }
+            | WindowEvent::Resized(_) => {
+                self.handler.send_event(event, space);
+            }
+            _ => {}
+        }
+        // switching to another application takes focus from the page too
+        let window_focused = main_window_focused || self.window.has_focus();
+        let focused = self.focused && window_focused && !egui_wants_keyboard;
+        self.handler.set_focused(focused);
+        if !focused {
+            self.handler.input().view().release_pointer();
+        }
+    }
+
+    /// Raw mouse movement, see `WinitEventToSurfaceProxy::pointer_motion`.
+    pub fn pointer_motion(&mut self, delta: (f64, f64)) {
+        self.handler.pointer_motion(delta);
+    }
+
+    /// Apply the guest's latest cursor and pointer mode to the page's window, see `View`.
+    ///
+    /// Platforms that cannot lock the pointer, such as X11, get it confined and hidden instead,
+    /// which looks the same to the page as mouse movement still arrives unbounded.
+    pub fn update_view(&mut self) {
+        let view = self.handler.input().view();
+        let Some(request) = view.take_request() else {
+            return;
+        };
+        if let Some(icon) = request.cursor {
+            self.window.set_cursor(icon);
+        }
+        let grab = |mode| self.window.set_cursor_grab(mode);
+        let result = match request.pointer {
+            PointerMode::Free => grab(CursorGrabMode::None),
+            PointerMode::Confined => grab(CursorGrabMode::Confined),
+            PointerMode::Locked => {
+                grab(CursorGrabMode::Locked).or_else(|_| grab(CursorGrabMode::Confined))
+            }
+        };
+        let mode = match result {
+            Ok(()) => request.pointer,
+            Err(e) => {
+                log::warn!("Failed to grab the pointer: {}", e);
+                let _ = grab(CursorGrabMode::None);
+                PointerMode::Free
+            }
+        };
+        view.set_pointer_mode(mode);
+        self.window
+            .set_cursor_visible(request.cursor.is_some() && mode != PointerMode::Locked);
+    }
+
+    /// Apply the guest's latest text input request to the page's window, see `TextInput`.
+    ///
+    /// Keys reach the page through either its own window or the main window, depending on
+    /// which the platform gave focus. The main window's IME belongs to egui-winit, which would
+    /// undo changes made behind its back, so it gets the cursor through egui instead, see
+    /// `main_window_ime_area`.
+    pub fn update_ime(&mut self) {
+        let Some(request) = self.handler.input().text_input().take_request() else {
+            return;
+        };
+        self.window.set_ime_allowed(request.enabled);
+        if request.enabled {
+            let (position, size) = self.handler.from_canvas(request.cursor, EventSpace::Page);
+            self.window.set_ime_cursor_area(position, size);
+        }
+    }
+
+    /// Where the guest's text cursor is in the main window while the page has keyboard focus
+    /// and a focused text field, for egui to place the IME candidate window.
+    pub fn main_window_ime_area(&self) -> Option<(PhysicalPosition<f64>, PhysicalSize<f64>)> {
+        if !self.focused {
+            return None;
+        }
+        let cursor = self.handler.input().text_input().cursor()?;
+        Some(self.handler.from_canvas(cursor, EventSpace::Parent))
+    }
+
+    /// Handle `event` if it belongs to one of the page's further canvas windows.
+    pub fn canvas_window_event(&mut self, window_id: WindowId, event: &WindowEvent) -> bool {
+        let Some((window, handler)) = self
+            .canvas_windows
+            .iter_mut()
+            .find(|(window, _)| window.id() == window_id)
+        else {
+            return false;
+        };
+        match event {
+            // the guest still holds the surface, so the window only goes away with the page
+            WindowEvent::CloseRequested => window.set_visible(false),
+            WindowEvent::Focused(focused) => handler.set_focused(*focused),
+            _ => handler.send_event(event, EventSpace::Page),
+        }
+        true
+    }
+
+    /// Start the page's next animation frame on all its canvases, `presented` being when the
+    /// main window's frame went out.
+    pub fn animation_frame(&mut self, presented: Instant) {
+        self.handler.animation_frame(presented);
+        for (window, handler) in &self.canvas_windows {
+            if window.is_visible() != Some(false) {
+                handler.canvas_frame();
+            }
+        }
+    }
+
+    /// A frame for a page kept running in the background, if one is due.
+    pub fn background_frame(&mut self, presented: Instant) {
+        let due = self
+            .last_background_frame
+            .is_none_or(|last| presented.duration_since(last) >= BACKGROUND_FRAME_INTERVAL);
+        if due {
+            self.last_background_frame = Some(presented);
+            self.animation_frame(presented);
+        }
+    }
+}
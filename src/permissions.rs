@@ -0,0 +1,300 @@
+//! Per-origin capabilities for wasm pages.
+//!
+//! A component asks for capabilities simply by importing the WASI interfaces that need them.
+//! Before a page is instantiated its imports are mapped to `Capability`s and sent to the UI as a
+//! `PermissionRequest`. Decisions the user has already made for the page's origin are answered
+//! straight from the `PermissionStore`, anything new is shown as a prompt. The resulting
+//! `Grants` decide how the page's `WasiCtx` is assembled: a denied capability is either left
+//! out or replaced with an inert stand-in, so the component still links but learns nothing.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use wasmtime_wasi::{DirPerms, FilePerms, HostMonotonicClock, HostWallClock, WasiCtxBuilder};
+
+use crate::cache::hex_digest;
+use crate::console::ConsolePipes;
+
+/// File in each origin's folder that names the origin, see `create_origin_dir`.
+pub const ORIGIN_FILE: &str = "origin";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Capability {
+    Filesystem,
+    Environment,
+    Clocks,
+    Random,
+    Network,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 5] = [
+        Capability::Filesystem,
+        Capability::Environment,
+        Capability::Clocks,
+        Capability::Random,
+        Capability::Network,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::Filesystem => "filesystem",
+            Capability::Environment => "environment",
+            Capability::Clocks => "clocks",
+            Capability::Random => "random",
+            Capability::Network => "network",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Capability::Filesystem => "Read and write files in its own folder",
+            Capability::Environment => "Read the browser's environment variables",
+            Capability::Clocks => "Read the current time and measure elapsed time",
+            Capability::Random => "Use random numbers from the system",
+            Capability::Network => "Open network connections and look up host names",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Capability> {
+        Capability::ALL.into_iter().find(|c| c.name() == name)
+    }
+
+    /// The capability needed by an import such as `wasi:clocks/wall-clock@0.2.3`.
+    pub fn for_import(import: &str) -> Option<Capability> {
+        let interface = import.split('@').next().unwrap_or(import);
+        if interface.starts_with("wasi:filesystem/") {
+            Some(Capability::Filesystem)
+        } else if interface == "wasi:cli/environment" {
+            Some(Capability::Environment)
+        } else if interface.starts_with("wasi:clocks/") {
+            Some(Capability::Clocks)
+        } else if interface.starts_with("wasi:random/") {
+            Some(Capability::Random)
+        } else if interface.starts_with("wasi:sockets/") {
+            Some(Capability::Network)
+        } else {
+            None
+        }
+    }
+
+    /// The capability needed by the WASI preview1 function `name`, as imported by core modules.
+    ///
+    /// The `fd_*` functions only reach the console unless the filesystem was granted, so opening
+    /// paths is what counts.
+    pub fn for_preview1_import(name: &str) -> Option<Capability> {
+        if name.starts_with("path_") {
+            Some(Capability::Filesystem)
+        } else if name.starts_with("environ_") {
+            Some(Capability::Environment)
+        } else if name.starts_with("clock_") {
+            Some(Capability::Clocks)
+        } else if name == "random_get" {
+            Some(Capability::Random)
+        } else if name.starts_with("sock_") {
+            Some(Capability::Network)
+        } else {
+            None
+        }
+    }
+}
+
+/// The origin a page's permissions are stored under, `scheme://host[:port]`.
+pub fn origin_of(location: &str) -> String {
+    match reqwest::Url::parse(location) {
+        Ok(url) => url.origin().ascii_serialization(),
+        Err(_) => location.to_string(),
+    }
+}
+
+/// The capabilities a page was given. Anything not in here is denied.
+#[derive(Clone, Debug, Default)]
+pub struct Grants {
+    allowed: BTreeSet<Capability>,
+}
+
+impl Grants {
+    pub fn allows(&self, capability: Capability) -> bool {
+        self.allowed.contains(&capability)
+    }
+}
+
+/// Sent from a page's runtime thread to the UI before the page is instantiated.
+pub struct PermissionRequest {
+    // the tab the page runs in
+    pub tab: i32,
+    pub origin: String,
+    pub capabilities: BTreeSet<Capability>,
+    pub reply: oneshot::Sender<Grants>,
+}
+
+/// Remembered decisions, one line per origin and capability in the user's config directory.
+pub struct PermissionStore {
+    path: Option<PathBuf>,
+    decisions: BTreeMap<String, BTreeMap<Capability, bool>>,
+}
+
+impl PermissionStore {
+    pub fn open_default() -> Self {
+        let path = dirs::config_dir().map(|dir| dir.join("m-browser").join("permissions"));
+        let mut decisions: BTreeMap<String, BTreeMap<Capability, bool>> = BTreeMap::new();
+        if let Some(contents) = path.as_ref().and_then(|p| fs::read_to_string(p).ok()) {
+            for line in contents.lines() {
+                let mut fields = line.split('\t');
+                let (Some(origin), Some(capability), Some(decision)) =
+                    (fields.next(), fields.next(), fields.next())
+                else {
+                    continue;
+                };
+                if let Some(capability) = Capability::from_name(capability) {
+                    decisions
+                        .entry(origin.to_string())
+                        .or_default()
+                        .insert(capability, decision == "allow");
+                }
+            }
+        }
+        Self { path, decisions }
+    }
+
+    pub fn decision(&self, origin: &str, capability: Capability) -> Option<bool> {
+        self.decisions.get(origin)?.get(&capability).copied()
+    }
+
+    pub fn remember(&mut self, origin: &str, capability: Capability, allowed: bool) {
+        self.decisions
+            .entry(origin.to_string())
+            .or_default()
+            .insert(capability, allowed);
+        if let Err(e) = self.save() {
+            log::warn!("Failed to save permissions: {}", e);
+        }
+    }
+
+    /// Capabilities in `requested` the user has not decided on yet for `origin`.
+    pub fn undecided(&self, origin: &str, requested: &BTreeSet<Capability>) -> BTreeSet<Capability> {
+        requested
+            .iter()
+            .copied()
+            .filter(|c| self.decision(origin, *c).is_none())
+            .collect()
+    }
+
+    pub fn grants(&self, origin: &str, requested: &BTreeSet<Capability>) -> Grants {
+        Grants {
+            allowed: requested
+                .iter()
+                .copied()
+                .filter(|c| self.decision(origin, *c) == Some(true))
+                .collect(),
+        }
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut contents = String::new();
+        for (origin, capabilities) in &self.decisions {
+            for (capability, allowed) in capabilities {
+                let decision = if *allowed { "allow" } else { "deny" };
+                contents.push_str(&format!("{}\t{}\t{}\n", origin, capability.name(), decision));
+            }
+        }
+        fs::write(path, contents)
+    }
+}
+
+/// Where each origin keeps its data, one folder per origin.
+pub fn origins_dir() -> PathBuf {
+    let base = dirs::data_dir().unwrap_or_else(std::env::temp_dir);
+    base.join("m-browser").join("origins")
+}
+
+/// The private folder of pages from `origin`, holding its storage and the `files` folder
+/// preopened as `/`.
+pub fn origin_dir(origin: &str) -> PathBuf {
+    origins_dir().join(hex_digest(origin.as_bytes()))
+}
+
+/// Create `origin`'s folder if needed. The folder is named by hash, so the origin is written
+/// next to it for the storage panel to list.
+pub fn create_origin_dir(origin: &str) -> io::Result<PathBuf> {
+    let dir = origin_dir(origin);
+    fs::create_dir_all(&dir)?;
+    let name = dir.join(ORIGIN_FILE);
+    if !name.exists() {
+        fs::write(name, origin)?;
+    }
+    Ok(dir)
+}
+
+/// Assemble the WASI context for a page from `origin` with the given grants, printing to the
+/// tab's console. Components take it as built, core modules through `build_p1`.
+pub fn wasi_ctx(
+    origin: &str,
+    grants: &Grants,
+    console: &ConsolePipes,
+) -> anyhow::Result<WasiCtxBuilder> {
+    let mut builder = WasiCtxBuilder::new();
+    builder.stdout(console.stdout());
+    builder.stderr(console.stderr());
+
+    if grants.allows(Capability::Filesystem) {
+        let dir = create_origin_dir(origin)?.join("files");
+        fs::create_dir_all(&dir)?;
+        builder.preopened_dir(&dir, "/", DirPerms::all(), FilePerms::all())?;
+    }
+    if grants.allows(Capability::Environment) {
+        builder.inherit_env();
+    }
+    if !grants.allows(Capability::Clocks) {
+        builder.wall_clock(FrozenClock);
+        builder.monotonic_clock(FrozenClock);
+    }
+    if !grants.allows(Capability::Random) {
+        // wasi:random has no way to fail, so a denied page gets a fixed stream instead
+        builder.secure_random(wasmtime_wasi::random::Deterministic::new(vec![0]));
+        builder.insecure_random(wasmtime_wasi::random::Deterministic::new(vec![0]));
+        builder.insecure_random_seed(0);
+    }
+    if grants.allows(Capability::Network) {
+        builder.inherit_network();
+        builder.allow_ip_name_lookup(true);
+    } else {
+        builder.allow_tcp(false);
+        builder.allow_udp(false);
+        builder.allow_ip_name_lookup(false);
+    }
+
+    Ok(builder)
+}
+
+/// Clock handed to pages without the clocks capability, it never moves.
+struct FrozenClock;
+
+impl HostWallClock for FrozenClock {
+    fn resolution(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn now(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+impl HostMonotonicClock for FrozenClock {
+    fn resolution(&self) -> u64 {
+        1_000_000_000
+    }
+
+    fn now(&self) -> u64 {
+        0
+    }
+}
@@ -0,0 +1,370 @@
+//! Drawing the browser's own UI without a GPU.
+//!
+//! When wgpu finds no adapter at all, as on CI boxes and many remote desktops, egui's meshes are
+//! rasterized on the CPU here and presented to the main window with softbuffer. Pages keep
+//! running in their own windows and draw to them with `wasi:frame-buffer`, which softbuffer
+//! presents as well.
+//!
+//! Blending happens in gamma space on premultiplied colors, which is what egui's tessellator
+//! is tuned for.
+
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+
+use egui::epaint::{
+    ClippedPrimitive, Color32, ImageData, ImageDelta, Mesh, Primitive, Rect, TextureId,
+    TexturesDelta, Vertex,
+};
+use winit::window::Window;
+
+/// Paints egui's output into a window with softbuffer.
+pub struct SoftwarePainter {
+    // the surface is only valid while its context is alive
+    _context: softbuffer::Context<Arc<Window>>,
+    surface: softbuffer::Surface<Arc<Window>, Arc<Window>>,
+    canvas: Canvas,
+}
+
+impl SoftwarePainter {
+    pub fn new(window: Arc<Window>) -> Result<Self, String> {
+        let context = softbuffer::Context::new(window.clone())
+            .map_err(|e| format!("failed to create a softbuffer context: {}", e))?;
+        let surface = softbuffer::Surface::new(&context, window)
+            .map_err(|e| format!("failed to create a softbuffer surface: {}", e))?;
+        Ok(Self {
+            _context: context,
+            surface,
+            canvas: Canvas::default(),
+        })
+    }
+
+    /// Rasterize `primitives` into a `size` sized frame and present it.
+    pub fn paint(
+        &mut self,
+        size: [u32; 2],
+        primitives: &[ClippedPrimitive],
+        textures: &TexturesDelta,
+        pixels_per_point: f32,
+    ) -> Result<(), String> {
+        for (id, delta) in &textures.set {
+            self.canvas.set_texture(*id, delta);
+        }
+        let painted = self.present(size, primitives, pixels_per_point);
+        for id in &textures.free {
+            self.canvas.textures.remove(id);
+        }
+        painted
+    }
+
+    fn present(
+        &mut self,
+        [width, height]: [u32; 2],
+        primitives: &[ClippedPrimitive],
+        pixels_per_point: f32,
+    ) -> Result<(), String> {
+        // a minimized window has nothing to show
+        let (Some(surface_width), Some(surface_height)) =
+            (NonZeroU32::new(width), NonZeroU32::new(height))
+        else {
+            return Ok(());
+        };
+
+        self.canvas.resize(width as usize, height as usize);
+        for primitive in primitives {
+            match &primitive.primitive {
+                Primitive::Mesh(mesh) => {
+                    self.canvas.draw_mesh(mesh, primitive.clip_rect, pixels_per_point)
+                }
+                // the browser paints no custom wgpu callbacks
+                Primitive::Callback(_) => {}
+            }
+        }
+
+        self.surface
+            .resize(surface_width, surface_height)
+            .map_err(|e| format!("failed to resize the softbuffer surface: {}", e))?;
+        let mut buffer = self
+            .surface
+            .buffer_mut()
+            .map_err(|e| format!("failed to get the softbuffer buffer: {}", e))?;
+        for (out, pixel) in buffer.iter_mut().zip(&self.canvas.pixels) {
+            // softbuffer wants 0RGB, the canvas is premultiplied over black already
+            *out = ((pixel.r() as u32) << 16) | ((pixel.g() as u32) << 8) | pixel.b() as u32;
+        }
+        buffer
+            .present()
+            .map_err(|e| format!("failed to present the softbuffer buffer: {}", e))
+    }
+}
+
+/// An image egui draws with, such as the font atlas.
+struct Texture {
+    size: [usize; 2],
+    pixels: Vec<Color32>,
+}
+
+impl Texture {
+    /// The texel at `(u, v)`, nearest neighbour.
+    fn sample(&self, u: f32, v: f32) -> Color32 {
+        let [width, height] = self.size;
+        if width == 0 || height == 0 {
+            return Color32::TRANSPARENT;
+        }
+        let x = ((u * width as f32) as usize).min(width - 1);
+        let y = ((v * height as f32) as usize).min(height - 1);
+        self.pixels[y * width + x]
+    }
+}
+
+/// The frame being rasterized, and the textures it is drawn with.
+#[derive(Default)]
+struct Canvas {
+    width: usize,
+    height: usize,
+    pixels: Vec<Color32>,
+    textures: HashMap<TextureId, Texture>,
+}
+
+impl Canvas {
+    /// Start a new `width` by `height` frame, all black.
+    fn resize(&mut self, width: usize, height: usize) {
+        self.width = width;
+        self.height = height;
+        self.pixels.clear();
+        self.pixels.resize(width * height, Color32::BLACK);
+    }
+
+    /// Create texture `id`, or update part of it.
+    fn set_texture(&mut self, id: TextureId, delta: &ImageDelta) {
+        let (size, pixels): ([usize; 2], Vec<Color32>) = match &delta.image {
+            ImageData::Color(image) => (image.size, image.pixels.clone()),
+            ImageData::Font(image) => (image.size, image.srgba_pixels(None).collect()),
+        };
+        let Some([left, top]) = delta.pos else {
+            self.textures.insert(id, Texture { size, pixels });
+            return;
+        };
+        let Some(texture) = self.textures.get_mut(&id) else {
+            log::warn!("Partial update of unknown texture {:?}", id);
+            return;
+        };
+        let [width, height] = texture.size;
+        for y in 0..size[1].min(height.saturating_sub(top)) {
+            for x in 0..size[0].min(width.saturating_sub(left)) {
+                texture.pixels[(top + y) * width + left + x] = pixels[y * size[0] + x];
+            }
+        }
+    }
+
+    /// Draw `mesh`'s triangles, only inside `clip`, which is in points.
+    fn draw_mesh(&mut self, mesh: &Mesh, clip: Rect, pixels_per_point: f32) {
+        let Canvas {
+            width,
+            height,
+            pixels,
+            textures,
+        } = self;
+        let Some(texture) = textures.get(&mesh.texture_id) else {
+            return;
+        };
+        let to_pixels = |points: f32, limit: usize| {
+            ((points * pixels_per_point).round().max(0.0) as usize).min(limit)
+        };
+        let clip = [
+            to_pixels(clip.min.x, *width),
+            to_pixels(clip.min.y, *height),
+            to_pixels(clip.max.x, *width),
+            to_pixels(clip.max.y, *height),
+        ];
+        for triangle in mesh.indices.chunks_exact(3) {
+            let vertices = [triangle[0], triangle[1], triangle[2]].map(|i| &mesh.vertices[i as usize]);
+            fill_triangle(pixels, *width, texture, vertices, clip, pixels_per_point);
+        }
+    }
+}
+
+/// Twice the signed area of the triangle `a`, `b`, `p`; positive when `p` is to one side of
+/// the edge from `a` to `b` and negative on the other.
+fn edge(a: (f32, f32), b: (f32, f32), p: (f32, f32)) -> f32 {
+    (b.0 - a.0) * (p.1 - a.1) - (b.1 - a.1) * (p.0 - a.0)
+}
+
+/// Blend the triangle `vertices` into `pixels`, a frame `width` pixels wide, inside `clip`
+/// (`[left, top, right, bottom]` in pixels).
+///
+/// A pixel belongs to the triangle if its center does. Centers exactly on an edge go to only
+/// one of the two triangles sharing it, so translucent shapes are not blended twice along the
+/// diagonals of their quads.
+fn fill_triangle(
+    pixels: &mut [Color32],
+    width: usize,
+    texture: &Texture,
+    mut vertices: [&Vertex; 3],
+    clip: [usize; 4],
+    pixels_per_point: f32,
+) {
+    let position = |vertex: &Vertex| (vertex.pos.x * pixels_per_point, vertex.pos.y * pixels_per_point);
+    let mut p = vertices.map(position);
+    let mut area = edge(p[0], p[1], p[2]);
+    if area == 0.0 {
+        return;
+    }
+    // one winding for every triangle, so each shared edge runs opposite ways in its two
+    if area < 0.0 {
+        vertices.swap(1, 2);
+        p.swap(1, 2);
+        area = -area;
+    }
+    let edges = [(p[1], p[2]), (p[2], p[0]), (p[0], p[1])];
+    let owns_edge = |(a, b): ((f32, f32), (f32, f32))| {
+        let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+        dy > 0.0 || (dy == 0.0 && dx < 0.0)
+    };
+    let owned = edges.map(owns_edge);
+
+    // the bounding box, within the clip rect
+    let xs = p.map(|p| p.0);
+    let ys = p.map(|p| p.1);
+    let lowest = |values: [f32; 3]| values[0].min(values[1]).min(values[2]).floor().max(0.0) as usize;
+    let highest = |values: [f32; 3]| values[0].max(values[1]).max(values[2]).ceil().max(0.0) as usize;
+    let (left, right) = (lowest(xs).max(clip[0]), highest(xs).min(clip[2]));
+    let (top, bottom) = (lowest(ys).max(clip[1]), highest(ys).min(clip[3]));
+
+    for y in top..bottom {
+        for x in left..right {
+            let center = (x as f32 + 0.5, y as f32 + 0.5);
+            let weights = edges.map(|(a, b)| edge(a, b, center));
+            let inside = weights
+                .iter()
+                .zip(owned)
+                .all(|(&weight, owned)| weight > 0.0 || (weight == 0.0 && owned));
+            if !inside {
+                continue;
+            }
+            let [w0, w1, w2] = weights.map(|weight| weight / area);
+            let u = w0 * vertices[0].uv.x + w1 * vertices[1].uv.x + w2 * vertices[2].uv.x;
+            let v = w0 * vertices[0].uv.y + w1 * vertices[1].uv.y + w2 * vertices[2].uv.y;
+            let channel = |get: fn(&Color32) -> u8| {
+                let value = w0 * get(&vertices[0].color) as f32
+                    + w1 * get(&vertices[1].color) as f32
+                    + w2 * get(&vertices[2].color) as f32;
+                value.round().clamp(0.0, 255.0) as u8
+            };
+            let color = Color32::from_rgba_premultiplied(
+                channel(Color32::r),
+                channel(Color32::g),
+                channel(Color32::b),
+                channel(Color32::a),
+            );
+            let source = multiply(color, texture.sample(u, v));
+            let pixel = &mut pixels[y * width + x];
+            *pixel = blend(source, *pixel);
+        }
+    }
+}
+
+/// Tint premultiplied `a` with premultiplied `b`.
+fn multiply(a: Color32, b: Color32) -> Color32 {
+    let scale = |a: u8, b: u8| ((a as u16 * b as u16 + 127) / 255) as u8;
+    Color32::from_rgba_premultiplied(
+        scale(a.r(), b.r()),
+        scale(a.g(), b.g()),
+        scale(a.b(), b.b()),
+        scale(a.a(), b.a()),
+    )
+}
+
+/// Premultiplied `source` over `target`.
+fn blend(source: Color32, target: Color32) -> Color32 {
+    let keep = 255 - source.a() as u16;
+    let over = |source: u8, target: u8| {
+        (source as u16 + (target as u16 * keep + 127) / 255).min(255) as u8
+    };
+    Color32::from_rgba_premultiplied(
+        over(source.r(), target.r()),
+        over(source.g(), target.g()),
+        over(source.b(), target.b()),
+        over(source.a(), target.a()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use egui::epaint::{pos2, ColorImage, TextureOptions};
+
+    /// A canvas `size` pixels square with egui's default, white texture.
+    fn canvas(size: usize) -> Canvas {
+        let mut canvas = Canvas::default();
+        canvas.resize(size, size);
+        let white = ColorImage::new([1, 1], Color32::WHITE);
+        canvas.set_texture(TextureId::default(), &ImageDelta::full(white, TextureOptions::NEAREST));
+        canvas
+    }
+
+    fn rect(min: f32, max: f32) -> Rect {
+        Rect::from_min_max(pos2(min, min), pos2(max, max))
+    }
+
+    fn square(color: Color32, min: f32, max: f32) -> Mesh {
+        let mut mesh = Mesh::default();
+        mesh.add_colored_rect(rect(min, max), color);
+        mesh
+    }
+
+    #[test]
+    fn meshes_cover_their_pixels() {
+        let mut canvas = canvas(4);
+        canvas.draw_mesh(&square(Color32::RED, 1.0, 3.0), rect(0.0, 4.0), 1.0);
+        for y in 0..4 {
+            for x in 0..4 {
+                let expected = if (1..3).contains(&x) && (1..3).contains(&y) {
+                    Color32::RED
+                } else {
+                    Color32::BLACK
+                };
+                assert_eq!(canvas.pixels[y * 4 + x], expected, "pixel {}, {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn translucent_quads_are_blended_once() {
+        let mut canvas = canvas(8);
+        let half = Color32::from_rgba_premultiplied(128, 128, 128, 128);
+        canvas.draw_mesh(&square(half, 0.0, 8.0), rect(0.0, 8.0), 1.0);
+        // blending twice would give 192 along the diagonal
+        let expected = Color32::from_rgba_premultiplied(128, 128, 128, 255);
+        assert!(canvas.pixels.iter().all(|&pixel| pixel == expected));
+    }
+
+    #[test]
+    fn meshes_are_clipped() {
+        let mut canvas = canvas(4);
+        canvas.draw_mesh(&square(Color32::RED, 0.0, 4.0), rect(0.0, 2.0), 1.0);
+        assert_eq!(canvas.pixels.iter().filter(|&&pixel| pixel == Color32::RED).count(), 4);
+    }
+
+    #[test]
+    fn points_are_scaled_to_pixels() {
+        let mut canvas = canvas(4);
+        canvas.draw_mesh(&square(Color32::RED, 0.0, 1.0), rect(0.0, 4.0), 2.0);
+        assert_eq!(canvas.pixels.iter().filter(|&&pixel| pixel == Color32::RED).count(), 4);
+    }
+
+    #[test]
+    fn partial_texture_updates_land_in_place() {
+        let mut canvas = canvas(1);
+        let id = TextureId::Managed(1);
+        let black = ColorImage::new([2, 2], Color32::BLACK);
+        canvas.set_texture(id, &ImageDelta::full(black, TextureOptions::NEAREST));
+        let red = ColorImage::new([1, 1], Color32::RED);
+        canvas.set_texture(id, &ImageDelta::partial([1, 1], red, TextureOptions::NEAREST));
+
+        let texture = &canvas.textures[&id];
+        assert_eq!(texture.pixels, vec![Color32::BLACK, Color32::BLACK, Color32::BLACK, Color32::RED]);
+        assert_eq!(texture.sample(0.75, 0.75), Color32::RED);
+    }
+}
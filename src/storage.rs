@@ -0,0 +1,347 @@
+//! Per-origin key-value storage for wasm pages, `m:browser/storage`.
+//!
+//! An origin's entries are kept in memory and written to one file in its folder, next to the
+//! `files` folder of the filesystem capability, after every change. All pages from an origin
+//! share one `OriginStore`, so two tabs never overwrite each other's writes. Keys and values
+//! together count against the origin's quota.
+//!
+//! The storage panel lists every origin folder through `Storage::usage` and clears one with
+//! `Storage::clear`, which removes its files as well.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::host::storage::{self, StorageError};
+use crate::permissions::{create_origin_dir, origin_dir, origins_dir, ORIGIN_FILE};
+
+/// Bytes of keys and values each origin may store, as with `localStorage`.
+pub const DEFAULT_QUOTA: u64 = 5 * 1024 * 1024;
+
+const STORE_FILE: &str = "storage";
+const MAGIC: &[u8; 4] = b"MKV1";
+
+/// One origin's entries, loaded on first use.
+struct OriginStore {
+    origin: String,
+    entries: BTreeMap<String, Vec<u8>>,
+}
+
+impl OriginStore {
+    fn open(origin: &str) -> Self {
+        let entries = match fs::read(origin_dir(origin).join(STORE_FILE)) {
+            Ok(bytes) => decode(&bytes).unwrap_or_else(|| {
+                log::warn!("Discarding unreadable storage of {}", origin);
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+        Self {
+            origin: origin.to_string(),
+            entries,
+        }
+    }
+
+    fn used(&self) -> u64 {
+        used(&self.entries)
+    }
+
+    /// Write the entries out, replacing the file in one step.
+    fn save(&self) -> io::Result<()> {
+        let path = create_origin_dir(&self.origin)?.join(STORE_FILE);
+        if self.entries.is_empty() {
+            return match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        let partial = path.with_extension(format!("partial-{}", std::process::id()));
+        fs::write(&partial, encode(&self.entries))?;
+        fs::rename(&partial, &path)
+    }
+}
+
+/// What an origin has stored, for the storage panel.
+#[derive(Clone, Debug)]
+pub struct OriginUsage {
+    pub origin: String,
+    /// Keys with the size of their values.
+    pub entries: Vec<(String, usize)>,
+    /// Bytes counted against the quota.
+    pub bytes: u64,
+    /// Bytes in the origin's `files` folder, which has no quota.
+    pub file_bytes: u64,
+}
+
+/// The storage of every origin, shared by all pages and the storage panel.
+#[derive(Clone)]
+pub struct Storage {
+    quota: u64,
+    open: Arc<Mutex<HashMap<String, Arc<Mutex<OriginStore>>>>>,
+}
+
+impl Storage {
+    pub fn new(quota: u64) -> Self {
+        Self {
+            quota,
+            open: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn quota(&self) -> u64 {
+        self.quota
+    }
+
+    /// The storage pages from `origin` see. It is read from disk on first use, on the page's
+    /// thread.
+    pub fn for_origin(&self, origin: &str) -> PageStorage {
+        PageStorage {
+            storage: self.clone(),
+            origin: origin.to_string(),
+            store: None,
+        }
+    }
+
+    fn store(&self, origin: &str) -> Arc<Mutex<OriginStore>> {
+        let mut open = self.open.lock().unwrap();
+        Arc::clone(
+            open.entry(origin.to_string())
+                .or_insert_with(|| Arc::new(Mutex::new(OriginStore::open(origin)))),
+        )
+    }
+
+    /// Every origin that stored entries or files, sorted by origin.
+    pub fn usage(&self) -> Vec<OriginUsage> {
+        let Ok(dirs) = fs::read_dir(origins_dir()) else {
+            return Vec::new();
+        };
+        let mut usage: Vec<OriginUsage> = dirs
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let origin = fs::read_to_string(entry.path().join(ORIGIN_FILE)).ok()?;
+                let store = self.store(&origin);
+                let store = store.lock().unwrap();
+                Some(OriginUsage {
+                    entries: store
+                        .entries
+                        .iter()
+                        .map(|(key, value)| (key.clone(), value.len()))
+                        .collect(),
+                    bytes: store.used(),
+                    file_bytes: dir_size(&entry.path().join("files")),
+                    origin,
+                })
+            })
+            .filter(|usage| !usage.entries.is_empty() || usage.file_bytes > 0)
+            .collect();
+        usage.sort_by(|a, b| a.origin.cmp(&b.origin));
+        usage
+    }
+
+    /// Remove everything `origin` stored, its entries and its files.
+    pub fn clear(&self, origin: &str) -> io::Result<()> {
+        self.store(origin).lock().unwrap().entries.clear();
+        match fs::remove_dir_all(origin_dir(origin)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A page's end of `m:browser/storage`.
+pub struct PageStorage {
+    storage: Storage,
+    origin: String,
+    store: Option<Arc<Mutex<OriginStore>>>,
+}
+
+impl PageStorage {
+    fn store(&mut self) -> Arc<Mutex<OriginStore>> {
+        let store = self
+            .store
+            .get_or_insert_with(|| self.storage.store(&self.origin));
+        Arc::clone(store)
+    }
+}
+
+impl storage::Host for PageStorage {
+    fn get(&mut self, key: String) -> Option<Vec<u8>> {
+        self.store().lock().unwrap().entries.get(&key).cloned()
+    }
+
+    fn set(&mut self, key: String, value: Vec<u8>) -> Result<(), StorageError> {
+        let quota = self.storage.quota;
+        let store = self.store();
+        let mut store = store.lock().unwrap();
+        if !fits(&store.entries, &key, &value, quota) {
+            return Err(StorageError::QuotaExceeded(quota));
+        }
+        let previous = store.entries.insert(key.clone(), value);
+        store.save().map_err(|e| {
+            // keep memory and disk in step
+            match previous {
+                Some(previous) => store.entries.insert(key, previous),
+                None => store.entries.remove(&key),
+            };
+            StorageError::Io(e.to_string())
+        })
+    }
+
+    fn delete(&mut self, key: String) -> Result<(), StorageError> {
+        let store = self.store();
+        let mut store = store.lock().unwrap();
+        let Some(previous) = store.entries.remove(&key) else {
+            return Ok(());
+        };
+        store.save().map_err(|e| {
+            store.entries.insert(key, previous);
+            StorageError::Io(e.to_string())
+        })
+    }
+
+    fn keys(&mut self) -> Vec<String> {
+        self.store().lock().unwrap().entries.keys().cloned().collect()
+    }
+
+    fn clear(&mut self) -> Result<(), StorageError> {
+        let store = self.store();
+        let mut store = store.lock().unwrap();
+        let previous = std::mem::take(&mut store.entries);
+        store.save().map_err(|e| {
+            store.entries = previous;
+            StorageError::Io(e.to_string())
+        })
+    }
+}
+
+fn used(entries: &BTreeMap<String, Vec<u8>>) -> u64 {
+    entries
+        .iter()
+        .map(|(key, value)| (key.len() + value.len()) as u64)
+        .sum()
+}
+
+/// Whether `entries` stay within `quota` with `value` stored under `key`, in place of any value
+/// the key had.
+fn fits(entries: &BTreeMap<String, Vec<u8>>, key: &str, value: &[u8], quota: u64) -> bool {
+    let replaced = entries
+        .get(key)
+        .map_or(0, |old| (key.len() + old.len()) as u64);
+    used(entries) - replaced + (key.len() + value.len()) as u64 <= quota
+}
+
+/// Total size of the files under `dir`, 0 if it does not exist.
+fn dir_size(dir: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.file_type() {
+            Ok(kind) if kind.is_dir() => dir_size(&entry.path()),
+            _ => entry.metadata().map_or(0, |metadata| metadata.len()),
+        })
+        .sum()
+}
+
+// The store file is `MAGIC` followed by the entries, each as a little-endian u32 length and
+// the key's bytes, then the same for the value.
+
+fn encode(entries: &BTreeMap<String, Vec<u8>>) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    for (key, value) in entries {
+        for field in [key.as_bytes(), value.as_slice()] {
+            bytes.extend_from_slice(&(field.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(field);
+        }
+    }
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Option<BTreeMap<String, Vec<u8>>> {
+    let mut rest = bytes.strip_prefix(MAGIC.as_slice())?;
+    let mut field = || -> Option<Vec<u8>> {
+        let (len, tail) = rest.split_first_chunk::<4>()?;
+        let len = u32::from_le_bytes(*len) as usize;
+        let (field, tail) = (tail.get(..len)?, tail.get(len..)?);
+        rest = tail;
+        Some(field.to_vec())
+    };
+    let mut entries = BTreeMap::new();
+    while let Some(key) = field() {
+        let key = String::from_utf8(key).ok()?;
+        entries.insert(key, field()?);
+    }
+    // a truncated entry ends the loop early and leaves bytes behind
+    if !rest.is_empty() {
+        return None;
+    }
+    Some(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(pairs: &[(&str, &[u8])]) -> BTreeMap<String, Vec<u8>> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn decode_reads_back_what_encode_wrote() {
+        let stored = entries(&[("name", b"value"), ("empty", b""), ("\u{e9}t\u{e9}", &[0, 255, 7])]);
+        assert_eq!(decode(&encode(&stored)), Some(stored));
+    }
+
+    #[test]
+    fn no_entries_encode_to_the_magic_alone() {
+        let bytes = encode(&BTreeMap::new());
+        assert_eq!(bytes, MAGIC.to_vec());
+        assert_eq!(decode(&bytes), Some(BTreeMap::new()));
+    }
+
+    #[test]
+    fn decode_rejects_other_files() {
+        assert_eq!(decode(b""), None);
+        assert_eq!(decode(b"MKV0"), None);
+    }
+
+    #[test]
+    fn decode_rejects_truncated_entries() {
+        let bytes = encode(&entries(&[("key", b"value")]));
+        for len in MAGIC.len() + 1..bytes.len() {
+            assert_eq!(decode(&bytes[..len]), None, "{} of {} bytes", len, bytes.len());
+        }
+    }
+
+    #[test]
+    fn decode_rejects_keys_that_are_not_utf8() {
+        let mut bytes = MAGIC.to_vec();
+        for field in [&[0xff, 0xfe][..], b"value"] {
+            bytes.extend_from_slice(&(field.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(field);
+        }
+        assert_eq!(decode(&bytes), None);
+    }
+
+    #[test]
+    fn keys_and_values_count_against_the_quota() {
+        let stored = entries(&[("ab", b"cde"), ("f", b"")]);
+        assert_eq!(used(&stored), 6);
+        assert!(fits(&stored, "g", b"123", 10));
+        assert!(!fits(&stored, "g", b"1234", 10));
+    }
+
+    #[test]
+    fn replacing_a_value_frees_the_old_one() {
+        let stored = entries(&[("key", b"0123456")]);
+        assert!(fits(&stored, "key", b"abcdefg", 10));
+        assert!(!fits(&stored, "key", b"abcdefgh", 10));
+        assert!(!fits(&stored, "other", b"", 10));
+    }
+}
@@ -0,0 +1,152 @@
+//! Text entry and input method composition for wasm pages, `m:browser/text-input`.
+//!
+//! A page enables text input while one of its text fields has focus and keeps the host told
+//! where the field's cursor is. `WasmPage::update_ime` turns that into IME enablement and the
+//! candidate window position on the page's window, and the app hands it to egui for the main
+//! window. Typed text comes back from
+//! `WinitEventToSurfaceProxy` as preedit and commit events, queued here until the guest takes
+//! them.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::host::text_input::{self, Preedit, Rect, TextEvent};
+
+/// Events kept for a page that never drains its queue. The oldest are dropped first.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// A rectangle in canvas pixels.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CanvasRect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl From<Rect> for CanvasRect {
+    fn from(rect: Rect) -> Self {
+        Self {
+            x: rect.x,
+            y: rect.y,
+            width: rect.width,
+            height: rect.height,
+        }
+    }
+}
+
+/// What the guest asked for, see `TextInput::take_request`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImeRequest {
+    pub enabled: bool,
+    /// The text cursor.
+    pub cursor: CanvasRect,
+}
+
+#[derive(Default)]
+struct State {
+    events: VecDeque<TextEvent>,
+    request: Option<ImeRequest>,
+    // the request changed since the UI thread last looked
+    changed: bool,
+}
+
+/// Shared between the UI thread, which applies requests and queues text, and the page's host
+/// state.
+#[derive(Clone, Default)]
+pub struct TextInput {
+    state: Arc<Mutex<State>>,
+}
+
+impl TextInput {
+    /// Whether the page has a focused text field.
+    pub fn enabled(&self) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .request
+            .is_some_and(|request| request.enabled)
+    }
+
+    /// The text cursor of the page's focused text field, if it has one.
+    pub fn cursor(&self) -> Option<CanvasRect> {
+        self.state
+            .lock()
+            .unwrap()
+            .request
+            .filter(|request| request.enabled)
+            .map(|request| request.cursor)
+    }
+
+    /// Queue text being composed, `cursor` being a byte range in `text`.
+    pub fn preedit(&self, text: String, cursor: Option<(usize, usize)>) {
+        self.push(TextEvent::Preedit(Preedit {
+            text,
+            cursor: cursor.map(|(start, end)| (start as u32, end as u32)),
+        }));
+    }
+
+    pub fn commit(&self, text: String) {
+        self.push(TextEvent::Commit(text));
+    }
+
+    fn push(&self, event: TextEvent) {
+        let mut state = self.state.lock().unwrap();
+        if state.events.len() == QUEUE_CAPACITY {
+            state.events.pop_front();
+        }
+        state.events.push_back(event);
+    }
+
+    /// The guest's latest request, if it changed since the last call.
+    pub fn take_request(&self) -> Option<ImeRequest> {
+        let mut state = self.state.lock().unwrap();
+        if !state.changed {
+            return None;
+        }
+        state.changed = false;
+        state.request
+    }
+
+    fn request(&self, request: ImeRequest) {
+        let mut state = self.state.lock().unwrap();
+        if state.request != Some(request) {
+            state.request = Some(request);
+            state.changed = true;
+        }
+    }
+}
+
+impl text_input::Host for TextInput {
+    fn enable(&mut self, cursor: Rect) {
+        self.request(ImeRequest {
+            enabled: true,
+            cursor: cursor.into(),
+        });
+    }
+
+    fn set_cursor(&mut self, cursor: Rect) {
+        let enabled = self.enabled();
+        self.request(ImeRequest {
+            enabled,
+            cursor: cursor.into(),
+        });
+    }
+
+    fn disable(&mut self) {
+        let cursor = self
+            .state
+            .lock()
+            .unwrap()
+            .request
+            .map_or(CanvasRect::default(), |request| request.cursor);
+        self.request(ImeRequest {
+            enabled: false,
+            cursor,
+        });
+    }
+
+    fn take_events(&mut self) -> Vec<TextEvent> {
+        self.state.lock().unwrap().events.drain(..).collect()
+    }
+}
@@ -0,0 +1,175 @@
+//! Cursor, pointer lock and fullscreen for wasm pages, `m:browser/view`.
+//!
+//! The guest's requests are kept in a `View` shared with the UI thread. `WasmPage::update_view`
+//! applies the cursor and pointer mode to the page's window, and `App` shows the current page
+//! fullscreen, with the toolbar and panels hidden, while it asks for that. Locking the pointer
+//! and going fullscreen need `UserActivation`, and `View::exit` undoes both when the user
+//! presses Escape.
+
+use std::sync::{Arc, Mutex};
+
+use winit::window::CursorIcon as WinitCursorIcon;
+
+use crate::host::view::{self, CursorIcon, PointerMode, ViewError};
+use crate::input::UserActivation;
+
+/// What the guest asked for, see `View::take_request`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ViewRequest {
+    /// `None` hides the cursor.
+    pub cursor: Option<WinitCursorIcon>,
+    pub pointer: PointerMode,
+    pub fullscreen: bool,
+}
+
+impl Default for ViewRequest {
+    fn default() -> Self {
+        Self {
+            cursor: Some(WinitCursorIcon::Default),
+            pointer: PointerMode::Free,
+            fullscreen: false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    request: ViewRequest,
+    // the request changed since the UI thread last looked
+    changed: bool,
+    // the pointer mode the page's window ended up in
+    pointer: Option<PointerMode>,
+}
+
+/// Shared between the UI thread, which applies requests, and the page's host state.
+#[derive(Clone, Default)]
+pub struct View {
+    state: Arc<Mutex<State>>,
+}
+
+impl View {
+    /// The guest's latest request, if it changed since the last call.
+    pub fn take_request(&self) -> Option<ViewRequest> {
+        let mut state = self.state.lock().unwrap();
+        if !state.changed {
+            return None;
+        }
+        state.changed = false;
+        Some(state.request)
+    }
+
+    fn update(&self, update: impl FnOnce(&mut ViewRequest)) {
+        let mut state = self.state.lock().unwrap();
+        let mut request = state.request;
+        update(&mut request);
+        if state.request != request {
+            state.request = request;
+            state.changed = true;
+        }
+    }
+
+    /// Record the pointer mode that was applied, which may fall short of the request.
+    pub fn set_pointer_mode(&self, mode: PointerMode) {
+        self.state.lock().unwrap().pointer = Some(mode);
+    }
+
+    pub fn pointer_mode(&self) -> PointerMode {
+        self.state.lock().unwrap().pointer.unwrap_or(PointerMode::Free)
+    }
+
+    pub fn pointer_locked(&self) -> bool {
+        self.pointer_mode() == PointerMode::Locked
+    }
+
+    pub fn fullscreen(&self) -> bool {
+        self.state.lock().unwrap().request.fullscreen
+    }
+
+    /// Let go of the pointer, for when the page loses focus.
+    pub fn release_pointer(&self) {
+        self.update(|request| request.pointer = PointerMode::Free);
+    }
+
+    /// Let go of the pointer and leave fullscreen. Returns whether there was anything to undo.
+    pub fn exit(&self) -> bool {
+        let held = {
+            let state = self.state.lock().unwrap();
+            state.request.pointer != PointerMode::Free
+                || state.pointer.is_some_and(|mode| mode != PointerMode::Free)
+                || state.request.fullscreen
+        };
+        self.update(|request| {
+            request.pointer = PointerMode::Free;
+            request.fullscreen = false;
+        });
+        held
+    }
+}
+
+/// A page's end of `m:browser/view`.
+pub struct PageView {
+    view: View,
+    activation: UserActivation,
+}
+
+impl PageView {
+    pub fn new(view: View, activation: UserActivation) -> Self {
+        Self { view, activation }
+    }
+
+    fn allowed(&self) -> bool {
+        self.activation.focused() && self.activation.is_active()
+    }
+}
+
+fn cursor_icon(icon: CursorIcon) -> Option<WinitCursorIcon> {
+    Some(match icon {
+        CursorIcon::Arrow => WinitCursorIcon::Default,
+        CursorIcon::Pointer => WinitCursorIcon::Pointer,
+        CursorIcon::Text => WinitCursorIcon::Text,
+        CursorIcon::Crosshair => WinitCursorIcon::Crosshair,
+        CursorIcon::Move => WinitCursorIcon::Move,
+        CursorIcon::Grab => WinitCursorIcon::Grab,
+        CursorIcon::Grabbing => WinitCursorIcon::Grabbing,
+        CursorIcon::NotAllowed => WinitCursorIcon::NotAllowed,
+        CursorIcon::Wait => WinitCursorIcon::Wait,
+        CursorIcon::Progress => WinitCursorIcon::Progress,
+        CursorIcon::Help => WinitCursorIcon::Help,
+        CursorIcon::EwResize => WinitCursorIcon::EwResize,
+        CursorIcon::NsResize => WinitCursorIcon::NsResize,
+        CursorIcon::NeswResize => WinitCursorIcon::NeswResize,
+        CursorIcon::NwseResize => WinitCursorIcon::NwseResize,
+        CursorIcon::Hidden => return None,
+    })
+}
+
+impl view::Host for PageView {
+    fn set_cursor(&mut self, icon: CursorIcon) {
+        // only shown over the page itself, so like CSS cursors it needs no activation
+        self.view.update(|request| request.cursor = cursor_icon(icon));
+    }
+
+    fn request_pointer_mode(&mut self, mode: PointerMode) -> Result<(), ViewError> {
+        if mode != PointerMode::Free && !self.allowed() {
+            return Err(ViewError::NotAllowed);
+        }
+        self.view.update(|request| request.pointer = mode);
+        Ok(())
+    }
+
+    fn pointer_mode(&mut self) -> PointerMode {
+        self.view.pointer_mode()
+    }
+
+    fn request_fullscreen(&mut self, fullscreen: bool) -> Result<(), ViewError> {
+        if fullscreen && !self.allowed() {
+            return Err(ViewError::NotAllowed);
+        }
+        self.view.update(|request| request.fullscreen = fullscreen);
+        Ok(())
+    }
+
+    fn fullscreen(&mut self) -> bool {
+        self.view.fullscreen()
+    }
+}
@@ -1,22 +1,50 @@
-use std::sync::{Arc, Mutex};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::sync::mpsc;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
 // use clap::Parser;
 use futures::executor::block_on;
-// use wasi_frame_buffer_wasmtime::WasiFrameBufferView;
+use futures::future::Either;
+use wasi_frame_buffer_wasmtime::WasiFrameBufferView;
 use wasi_graphics_context_wasmtime::WasiGraphicsContextView;
 use wasi_surface_wasmtime::{Surface, SurfaceDesc, WasiSurfaceView};
 use wasi_webgpu_wasmtime::WasiWebGpuView;
 use wasmtime::{
-    component::{Component, Linker},
-    Config, Engine, Store,
+    component::{types::ComponentItem, Component, Linker, LinkerInstance, ResourceType},
+    CallHook, Config, Engine, ExternType, InstanceAllocationStrategy, InstancePre, Module, PoolingAllocationConfig, ResourcesRequired, Store,
+    UpdateDeadline, WasmBacktraceDetails,
 };
 
-use wasmtime_wasi::{IoView, ResourceTable, WasiCtx, WasiCtxBuilder, WasiView};
+use wasmtime_wasi::bindings::CommandPre;
+use wasmtime_wasi::preview1::{self, WasiP1Ctx};
+use wasmtime_wasi::{I32Exit, IoView, ResourceTable, WasiCtx, WasiCtxBuilder, WasiView};
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+use wasmtime_wasi_http::types::{HostFutureIncomingResponse, OutgoingRequestConfig};
+use wasmtime_wasi_http::{HttpResult, WasiHttpCtx, WasiHttpView};
 use winit::window::Window;
 
-use crate::winit_wasi::MyWindowWrapper;
+use crate::cache::CompiledCache;
+use crate::chrome::PageChrome;
+use crate::clipboard::PageClipboard;
+use crate::console::ConsolePipes;
+use crate::content::{sniff_wasm, ContentKind};
+use crate::failure::{FailureKind, WasmFailure};
+use crate::frames::FrameClock;
+use crate::host;
+use crate::input::InputQueue;
+use crate::limits::{CpuBudget, LimitExceeded, PageLimiter, PageLimits};
+use crate::network::PageNetwork;
+use crate::permissions::{origin_of, wasi_ctx, Capability, PermissionRequest};
+use crate::storage::PageStorage;
+use crate::text_input::TextInput;
+use crate::view::PageView;
+use crate::winit_wasi::{MyWindowWrapper, NoWindow, WasiWinitEventLoopProxy};
 
 // #[derive(clap::Parser, Debug)]
 // struct RuntimeArgs {
@@ -42,28 +70,64 @@ use crate::winit_wasi::MyWindowWrapper;
 struct HostState {
     pub table: ResourceTable,
     pub ctx: WasiCtx,
+    // WASI for core modules, which see preview1 instead of `ctx`
+    pub p1: WasiP1Ctx,
     pub wgpu_instance: Arc<wgpu_core::global::Global>,
     // pub surface_proxy: Option<wasi_surface_wasmtime::SurfaceProxy>,
-    // pub main_thread_proxy: wasi_surface_wasmtime::WasiWinitEventLoopProxy,
+    pub main_thread: WasiWinitEventLoopProxy,
     pub surface: Arc<Mutex<Option<Surface>>>,
+    // width and height the page asked for its first canvas, in logical pixels
+    pub canvas_size: Arc<Mutex<Option<(Option<u32>, Option<u32>)>>>,
+    // why the last canvas came without a window, raised as a trap once the host call returns
+    pub canvas_error: Mutex<Option<String>>,
+    pub limiter: PageLimiter,
+    pub input: InputQueue,
+    pub text_input: TextInput,
+    pub frames: FrameClock,
+    pub clipboard: PageClipboard,
+    pub chrome: PageChrome,
+    pub storage: PageStorage,
+    pub http: WasiHttpCtx,
+    pub network: PageNetwork,
+    pub view: PageView,
 }
 
 impl HostState {
-    // fn new(main_thread_proxy: wasi_surface_wasmtime::WasiWinitEventLoopProxy) -> Self {
-    fn new() -> Self {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        limits: PageLimits,
+        main_thread: WasiWinitEventLoopProxy,
+        wgpu_instance: Arc<wgpu_core::global::Global>,
+        input: InputQueue,
+        frames: FrameClock,
+        chrome: PageChrome,
+        storage: PageStorage,
+        network: PageNetwork,
+    ) -> Self {
+        let clipboard = PageClipboard::new(input.activation(), main_thread.clone());
+        let text_input = input.text_input();
+        let view = PageView::new(input.view(), input.activation());
         Self {
             table: ResourceTable::new(),
-            ctx: WasiCtxBuilder::new().inherit_stdio().build(),
-            wgpu_instance: Arc::new(wgpu_core::global::Global::new(
-                "webgpu",
-                &wgpu_types::InstanceDescriptor {
-                    backends: wgpu_types::Backends::all(),
-                    flags: wgpu_types::InstanceFlags::from_build_config(),
-                    backend_options: Default::default(),
-                },
-            )),
+            // nothing at all until the user answered the permission prompt, see `run_wasm`
+            ctx: WasiCtxBuilder::new().build(),
+            p1: WasiCtxBuilder::new().build_p1(),
+            wgpu_instance,
             // surface_proxy: None,
+            main_thread,
             surface: Arc::new(Mutex::new(None)),
+            canvas_size: Arc::new(Mutex::new(None)),
+            canvas_error: Mutex::new(None),
+            limiter: PageLimiter::new(limits),
+            input,
+            text_input,
+            frames,
+            clipboard,
+            chrome,
+            storage,
+            http: WasiHttpCtx::new(),
+            network,
+            view,
         }
     }
 }
@@ -78,13 +142,27 @@ impl WasiView for HostState {
         &mut self.ctx
     }
 }
+impl WasiHttpView for HostState {
+    fn ctx(&mut self) -> &mut WasiHttpCtx {
+        &mut self.http
+    }
+
+    fn send_request(
+        &mut self,
+        request: hyper::Request<HyperOutgoingBody>,
+        config: OutgoingRequestConfig,
+    ) -> HttpResult<HostFutureIncomingResponse> {
+        self.network.send_request(request, config)
+    }
+}
 
 
 
 impl WasiGraphicsContextView for HostState {}
-// impl WasiFrameBufferView for HostState {}
+impl WasiFrameBufferView for HostState {}
 
-struct UiThreadSpawner;
+/// Runs wasi-webgpu's main-thread work on the browser's event loop, where the windows live.
+struct UiThreadSpawner(WasiWinitEventLoopProxy);
 
 impl wasi_webgpu_wasmtime::MainThreadSpawner for UiThreadSpawner {
     async fn spawn<F, T>(&self, f: F) -> T
@@ -92,9 +170,7 @@ impl wasi_webgpu_wasmtime::MainThreadSpawner for UiThreadSpawner {
         F: FnOnce() -> T + Send + Sync + 'static,
         T: Send + Sync + 'static,
     {
-        // self.0.spawn(f).await
-        println!("spawning");
-        f()
+        self.0.spawn(f).await
     }
 }
 
@@ -104,19 +180,27 @@ impl WasiWebGpuView for HostState {
     }
 
     fn ui_thread_spawner(&self) -> Box<UiThreadSpawner> {
-        println!("ui_thread_spawner");
-        // todo!()
-        Box::new(UiThreadSpawner)
+        Box::new(UiThreadSpawner(self.main_thread.clone()))
     }
 }
 
 impl WasiSurfaceView for HostState {
     fn create_canvas(&self, desc: SurfaceDesc) -> Surface {
-        println!("create_canvas");
-        // block_on(self.main_thread_proxy.create_window(desc))
-        // todo!()
-        self.surface.lock().unwrap().take().unwrap()
-        // wasi_surface_wasmtime::Surface::new(Box::new(MyWindowWrapper(surface)))
+        // the first canvas is the page itself, laid over the central panel
+        if let Some(surface) = self.surface.lock().unwrap().take() {
+            *self.canvas_size.lock().unwrap() = Some((desc.width, desc.height));
+            return surface;
+        }
+        // any further canvas gets a window of its own, which only the main thread can create
+        match block_on(self.main_thread.create_window(self.chrome.tab(), desc)) {
+            Some(surface) => surface,
+            None => {
+                // wasi:surface has no way to fail here, so the guest traps on its way back
+                *self.canvas_error.lock().unwrap() =
+                    Some("the browser could not open a window for another canvas".to_string());
+                Surface::new(Box::new(NoWindow))
+            }
+        }
     }
 }
 
@@ -127,32 +211,136 @@ impl WasiSurfaceView for HostState {
 // }
 
 
-pub struct Wasm {
+/// Pages that can run at the same time, which sizes the pooling allocator.
+const MAX_PAGES: u32 = 16;
+/// Components kept ready to instantiate, see `Runtime::prepare`.
+const MAX_PREPARED: usize = 16;
+
+/// The part of the wasm runtime shared by every page.
+///
+/// There is one engine with a pooling instance allocator, one linker with all host interfaces,
+/// one wgpu instance and one epoch ticker for the lifetime of the browser. A page only gets a
+/// fresh `Store`, and a component that ran before is instantiated from its ready `CommandPre`
+/// without compiling or linking again.
+///
+/// Core modules, which most toolchains still emit by default, run too: they are linked against
+/// WASI preview1 and have no graphics, so their page shows the console.
+///
+/// Pages draw either with WebGPU or, through `wasi:frame-buffer`, into pixel buffers that
+/// softbuffer presents without a GPU. On machines where wgpu finds no adapter at all, such as
+/// CI boxes and remote desktops, the browser switches to software by itself: its own UI is
+/// rasterized on the CPU (see `SoftwarePainter`), WebGPU reports no adapter to pages and they
+/// draw through the frame buffer instead. Only a page that imports WebGPU and no frame buffer
+/// has nothing to fall back to, it gets an error page saying so.
+#[derive(Clone)]
+pub struct Runtime {
     engine: Engine,
-    linker: Linker<HostState>,
-    store: Store<HostState>,
-    surface: Arc<Mutex<Option<Surface>>>,
+    linker: Arc<Linker<HostState>>,
+    module_linker: Arc<wasmtime::Linker<HostState>>,
+    compiled: Option<Arc<CompiledCache>>,
+    wgpu_instance: Arc<wgpu_core::global::Global>,
+    // whether `wgpu_instance` found an adapter, see `gpu_available`
+    gpu: bool,
+    // cached components are named after their contents, so a path always means one component;
+    // each is kept with when a page last used it
+    prepared: Arc<Mutex<HashMap<PathBuf, (Instant, Prepared)>>>,
+    limits: PageLimits,
 }
-impl Wasm {
-    pub fn new(rx: mpsc::Receiver<()>) -> anyhow::Result<Wasm> {
+
+/// A component or core module ready to instantiate, see `Runtime::prepare`.
+#[derive(Clone)]
+enum Prepared {
+    Component(Component, CommandPre<HostState>),
+    Module(Module, InstancePre<HostState>),
+}
+
+impl Prepared {
+    /// What the page will ask the user for, going by its imports.
+    fn capabilities(&self, engine: &Engine) -> BTreeSet<Capability> {
+        match self {
+            Prepared::Component(component, _) => component
+                .component_type()
+                .imports(engine)
+                .filter_map(|(name, _)| Capability::for_import(name))
+                .collect(),
+            Prepared::Module(module, _) => module
+                .imports()
+                .filter(|import| import.module() == "wasi_snapshot_preview1")
+                .filter_map(|import| Capability::for_preview1_import(import.name()))
+                .collect(),
+        }
+    }
+
+    /// The memories and tables the page creates, `None` for a component that instantiates
+    /// modules it imports.
+    fn resources_required(&self) -> Option<ResourcesRequired> {
+        match self {
+            Prepared::Component(component, _) => component.resources_required(),
+            Prepared::Module(module, _) => Some(module.resources_required()),
+        }
+    }
+
+    /// Whether the page can never create a canvas.
+    fn headless(&self, engine: &Engine) -> bool {
+        match self {
+            Prepared::Component(component, _) => !component
+                .component_type()
+                .imports(engine)
+                .any(|(name, _)| name.starts_with("wasi:surface/")),
+            Prepared::Module(..) => true,
+        }
+    }
+
+    /// Whether the page draws with WebGPU and has no frame buffer to fall back to.
+    fn needs_gpu(&self, engine: &Engine) -> bool {
+        let Prepared::Component(component, _) = self else {
+            return false;
+        };
+        let imports: Vec<String> = component
+            .component_type()
+            .imports(engine)
+            .map(|(name, _)| name.to_string())
+            .collect();
+        let imports_any = |prefix: &str| imports.iter().any(|name| name.starts_with(prefix));
+        imports_any("wasi:webgpu/") && !imports_any("wasi:frame-buffer/")
+    }
+}
+
+impl Runtime {
+    pub fn new(limits: PageLimits) -> anyhow::Result<Runtime> {
         // env_logger::builder()
         //     .filter_level(log::LevelFilter::Info)
         //     .init();
 
         // let args = RuntimeArgs::parse();
 
-        let mut config = Config::default();
-        config.wasm_component_model(true);
-        config.async_support(true);
-        config.epoch_interruption(true);
-        let engine = Engine::new(&config)?;
+        let engine = match Engine::new(&engine_config(&limits, true)) {
+            Ok(engine) => engine,
+            Err(e) => {
+                // the pool reserves a lot of address space up front, which can be refused
+                log::warn!("Pooling allocator unavailable, allocating on demand: {:#}", e);
+                Engine::new(&engine_config(&limits, false))?
+            }
+        };
         let mut linker: Linker<HostState> = Linker::new(&engine);
 
         wasi_webgpu_wasmtime::add_to_linker(&mut linker)?;
-        // wasi_frame_buffer_wasmtime::add_to_linker(&mut linker)?;
+        wasi_frame_buffer_wasmtime::add_to_linker(&mut linker)?;
         wasi_graphics_context_wasmtime::add_to_linker(&mut linker)?;
         wasi_surface_wasmtime::add_only_surface_to_linker(&mut linker)?;
         wasmtime_wasi::add_to_linker_sync(&mut linker)?;
+        // same-origin by default, see `PageNetwork`
+        wasmtime_wasi_http::add_only_http_to_linker_sync(&mut linker)?;
+        host::input::add_to_linker(&mut linker, |state: &mut HostState| &mut state.input)?;
+        host::text_input::add_to_linker(&mut linker, |state: &mut HostState| &mut state.text_input)?;
+        host::frame::add_to_linker(&mut linker, |state: &mut HostState| &mut state.frames)?;
+        host::chrome::add_to_linker(&mut linker, |state: &mut HostState| &mut state.chrome)?;
+        host::clipboard::add_to_linker(&mut linker, |state: &mut HostState| &mut state.clipboard)?;
+        host::storage::add_to_linker(&mut linker, |state: &mut HostState| &mut state.storage)?;
+        host::view::add_to_linker(&mut linker, |state: &mut HostState| &mut state.view)?;
+
+        let mut module_linker: wasmtime::Linker<HostState> = wasmtime::Linker::new(&engine);
+        preview1::add_to_linker_async(&mut module_linker, |state: &mut HostState| &mut state.p1)?;
 
         // fn type_annotate<F>(val: F) -> F
         // where
@@ -163,83 +351,646 @@ impl Wasm {
         // let closure = type_annotate::<_>(|t| t);
         // Example::add_to_linker_imports_get_host(&mut linker, closure)?;
 
-        // let (main_thread_loop, main_thread_proxy) =
-        //     wasi_surface_wasmtime::create_wasi_winit_event_loop();
-        // wasi_surface_wasmtime:
-        let host_state = HostState::new();
+        let compiled = match CompiledCache::open_default() {
+            Ok(compiled) => Some(Arc::new(compiled)),
+            Err(e) => {
+                log::warn!("Failed to open compiled component cache: {}", e);
+                None
+            }
+        };
+
+        let wgpu_instance = Arc::new(wgpu_core::global::Global::new(
+            "webgpu",
+            &wgpu_types::InstanceDescriptor {
+                backends: wgpu_types::Backends::all(),
+                flags: wgpu_types::InstanceFlags::from_build_config(),
+                backend_options: Default::default(),
+            },
+        ));
+        let gpu = gpu_available(&wgpu_instance);
+
+        // one ticker drives the epoch deadlines of every page, until the engine is dropped
+        let ticker = engine.weak();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(EPOCH_TICK);
+            match ticker.upgrade() {
+                Some(engine) => engine.increment_epoch(),
+                None => break,
+            }
+        });
+
+        Ok(Runtime {
+            engine,
+            linker: Arc::new(linker),
+            module_linker: Arc::new(module_linker),
+            compiled,
+            wgpu_instance,
+            gpu,
+            prepared: Arc::new(Mutex::new(HashMap::new())),
+            limits,
+        })
+    }
+
+    /// Load, link and pre-instantiate the component or core module at `wasm_path`, or reuse
+    /// the result from an earlier page.
+    fn prepare(&self, wasm_path: &Path) -> Result<Prepared, ExitStatus> {
+        if let Some((used, prepared)) = self.prepared.lock().unwrap().get_mut(wasm_path) {
+            *used = Instant::now();
+            return Ok(prepared.clone());
+        }
+
+        let load_failed = |e: anyhow::Error| ExitStatus::Failed(WasmFailure::new(FailureKind::Load, &e));
+        let prepared = match wasm_kind(wasm_path).map_err(load_failed)? {
+            ContentKind::WasmModule => {
+                let module = match &self.compiled {
+                    Some(compiled) => compiled.load_or_compile(&self.engine, wasm_path),
+                    None => Module::from_file(&self.engine, wasm_path),
+                }
+                .map_err(load_failed)?;
+                let pre = self.module_linker.instantiate_pre(&module).map_err(|e| {
+                    ExitStatus::Failed(WasmFailure::link(&e, self.unsatisfied_module_imports(&module)))
+                })?;
+                Prepared::Module(module, pre)
+            }
+            _ => {
+                let component = match &self.compiled {
+                    Some(compiled) => compiled.load_or_compile(&self.engine, wasm_path),
+                    None => Component::from_file(&self.engine, wasm_path)
+                        .context("Component file not found"),
+                }
+                .map_err(load_failed)?;
+                let pre = self.linker.instantiate_pre(&component).map_err(|e| {
+                    ExitStatus::Failed(WasmFailure::link(&e, self.unsatisfied_imports(&component)))
+                })?;
+                let command = CommandPre::new(pre).map_err(|e| {
+                    let e = e.context("the component does not export wasi:cli/run");
+                    ExitStatus::Failed(WasmFailure::new(FailureKind::Instantiate, &e))
+                })?;
+                Prepared::Component(component, command)
+            }
+        };
+
+        let mut cached = self.prepared.lock().unwrap();
+        if cached.len() >= MAX_PREPARED {
+            // make room by dropping the one used longest ago
+            let oldest = cached
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                cached.remove(&oldest);
+            }
+        }
+        cached.insert(wasm_path.to_path_buf(), (Instant::now(), prepared.clone()));
+        Ok(prepared)
+    }
+
+    /// Imports of `component` the linker has no definition for. Each one is stubbed out on a
+    /// copy of the linker, which refuses anything it already defines.
+    fn unsatisfied_imports(&self, component: &Component) -> Vec<String> {
+        let mut probe = (*self.linker).clone();
+        let mut root = probe.root();
+        let mut missing = Vec::new();
+        for (name, item) in component.component_type().imports(&self.engine) {
+            probe_import(&mut root, &self.engine, name, name, &item, &mut missing);
+        }
+        missing
+    }
+
+    /// Imports of `module` the module linker has no definition for, see `unsatisfied_imports`.
+    fn unsatisfied_module_imports(&self, module: &Module) -> Vec<String> {
+        let mut probe = (*self.module_linker).clone();
+        module
+            .imports()
+            .filter(|import| match import.ty() {
+                ExternType::Func(ty) => probe
+                    .func_new(import.module(), import.name(), ty, |_, _, _| {
+                        anyhow::bail!("not linked")
+                    })
+                    .is_ok(),
+                // the module linker only ever defines functions
+                _ => true,
+            })
+            .map(|import| format!("{}::{}", import.module(), import.name()))
+            .collect()
+    }
+}
+
+/// Define a stub for the import `name` of type `item` in `instance`, adding `path` to `missing`
+/// when the linker took it. An interface none of whose items are defined counts as one import.
+fn probe_import(
+    instance: &mut LinkerInstance<'_, HostState>,
+    engine: &Engine,
+    name: &str,
+    path: &str,
+    item: &ComponentItem,
+    missing: &mut Vec<String>,
+) {
+    let unsatisfied = match item {
+        ComponentItem::ComponentInstance(ty) => {
+            let Ok(mut nested) = instance.instance(name) else {
+                return;
+            };
+            let mut items = 0;
+            let mut nested_missing = Vec::new();
+            for (export, item) in ty.exports(engine) {
+                if matches!(item, ComponentItem::Type(_)) {
+                    continue;
+                }
+                items += 1;
+                let path = format!("{}#{}", path, export);
+                probe_import(&mut nested, engine, export, &path, &item, &mut nested_missing);
+            }
+            if items == 0 || nested_missing.len() < items {
+                missing.extend(nested_missing);
+                return;
+            }
+            true
+        }
+        ComponentItem::ComponentFunc(_) => instance
+            .func_new(name, |_, _, _| anyhow::bail!("not linked"))
+            .is_ok(),
+        ComponentItem::Resource(_) => instance
+            .resource(name, ResourceType::host::<()>(), |_, _| Ok(()))
+            .is_ok(),
+        // types come along with the interfaces that use them
+        _ => false,
+    };
+    if unsatisfied {
+        missing.push(path.to_string());
+    }
+}
+
+/// Whether the file at `wasm_path` holds a component or a core module, going by its preamble.
+fn wasm_kind(wasm_path: &Path) -> anyhow::Result<ContentKind> {
+    let mut preamble = [0; 8];
+    File::open(wasm_path)
+        .and_then(|mut file| file.read_exact(&mut preamble))
+        .context("Component file not found")?;
+    sniff_wasm(&preamble).context("not a wasm component or module")
+}
+
+fn engine_config(limits: &PageLimits, pooling: bool) -> Config {
+    let mut config = Config::default();
+    config.wasm_component_model(true);
+    config.async_support(true);
+    config.epoch_interruption(true);
+    // symbolicate traps with DWARF when the component has it, for the error page
+    config.wasm_backtrace_details(WasmBacktraceDetails::Enable);
+    if pooling {
+        let mut pool = PoolingAllocationConfig::default();
+        pool.total_component_instances(MAX_PAGES);
+        pool.total_core_instances(MAX_PAGES * limits.max_instances as u32);
+        pool.total_memories(MAX_PAGES * limits.max_memories as u32);
+        pool.total_tables(MAX_PAGES * limits.max_tables as u32);
+        pool.max_memory_size(limits.max_memory_bytes);
+        pool.table_elements(limits.max_table_elements);
+        config.allocation_strategy(InstanceAllocationStrategy::Pooling(pool));
+    }
+    config
+}
+
+/// Whether wgpu finds a graphics adapter for WebGPU pages, a software one counting too.
+fn gpu_available(instance: &wgpu_core::global::Global) -> bool {
+    let options = wgpu_core::instance::RequestAdapterOptions {
+        power_preference: wgpu_types::PowerPreference::default(),
+        force_fallback_adapter: false,
+        compatible_surface: None,
+    };
+    match instance.request_adapter(&options, wgpu_types::Backends::all(), None) {
+        Ok(adapter) => {
+            instance.adapter_drop(adapter);
+            true
+        }
+        Err(e) => {
+            log::warn!("No graphics adapter, pages can only draw to a frame buffer: {}", e);
+            false
+        }
+    }
+}
+
+/// One page's share of the runtime: a fresh store on the shared engine.
+pub struct Wasm {
+    runtime: Runtime,
+    store: Store<HostState>,
+    surface: Arc<Mutex<Option<Surface>>>,
+    // set once the page turns out to have no graphics
+    headless: Arc<AtomicBool>,
+    // started right before the guest runs, see `start_clock`
+    budget: CpuBudget,
+    permissions: mpsc::Sender<PermissionRequest>,
+}
+impl Wasm {
+    /// A page reading extended input from `input` and frame times from `frames`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        runtime: &Runtime,
+        permissions: mpsc::Sender<PermissionRequest>,
+        input: InputQueue,
+        frames: FrameClock,
+        chrome: PageChrome,
+        storage: PageStorage,
+        network: PageNetwork,
+        main_thread: WasiWinitEventLoopProxy,
+    ) -> Wasm {
+        let host_state = HostState::new(
+            runtime.limits,
+            main_thread,
+            Arc::clone(&runtime.wgpu_instance),
+            input,
+            frames,
+            chrome,
+            storage,
+            network,
+        );
         let surface = Arc::clone(&host_state.surface);
 
-        let mut store = Store::new(&engine, host_state);
+        let mut store = Store::new(&runtime.engine, host_state);
+        store.limiter(|state| &mut state.limiter);
         store.set_epoch_deadline(1);
 
-        // main_thread_loop.run();
-        let e2 = engine.clone();
-        std::thread::spawn(move || {
-            loop {
-                std::thread::sleep(std::time::Duration::from_millis(100));
-                if rx.try_recv().is_ok() {
-                    println!("Exiting epoch thread");
-                    e2.increment_epoch();
-                    break;
+        Wasm {
+            runtime: runtime.clone(),
+            store,
+            surface,
+            headless: Arc::new(AtomicBool::new(false)),
+            budget: CpuBudget::new(runtime.limits.frame_cpu_budget),
+            permissions,
+        }
+    }
+
+    /// Run the component at `wasm_path`, loaded from `location`, on its own thread, drawing
+    /// to `surface` and printing to `console`.
+    ///
+    /// The returned handle controls the running guest. Dropping it does not stop the guest,
+    /// call `WasmInstance::terminate` for that.
+    pub fn start(
+        mut self,
+        wasm_path: PathBuf,
+        location: String,
+        surface: Surface,
+        console: ConsolePipes,
+    ) -> WasmInstance {
+        let control = Arc::new(Control {
+            state: Mutex::new(RunState::Running),
+            changed: Condvar::new(),
+        });
+
+        // Every epoch tick the guest checks in with the controller: it parks while paused,
+        // traps once terminated or over its CPU budget, and otherwise yields so a pending kill
+        // is noticed.
+        let callback_control = Arc::clone(&control);
+        let budget = self.budget.clone();
+        let callback_budget = budget.clone();
+        self.store.epoch_deadline_callback(move |_| {
+            let mut state = callback_control.state.lock().unwrap();
+            if *state == RunState::Paused {
+                let paused = Instant::now();
+                while *state == RunState::Paused {
+                    state = callback_control.changed.wait(state).unwrap();
                 }
+                callback_budget.resumed(paused.elapsed());
             }
+            if *state == RunState::Terminating {
+                return Err(anyhow::anyhow!("terminated by the browser"));
+            }
+            callback_budget.check()?;
+            Ok(UpdateDeadline::Yield(1))
+        });
+        // the guest picks up frames through host calls, each new one starts a fresh budget
+        self.store.call_hook(move |store, hook| {
+            if matches!(hook, CallHook::ReturningFromHost) {
+                if let Some(message) = store.data().canvas_error.lock().unwrap().take() {
+                    return Err(anyhow::anyhow!(message));
+                }
+                budget.returned_from_host(store.data().frames.current().number);
+            }
+            Ok(())
         });
 
-        Ok(Wasm {
-            engine,
-            linker,
-            store,
-            surface,
-        })
+        let instance_engine = self.runtime.engine.clone();
+        let canvas_size = Arc::clone(&self.store.data().canvas_size);
+        let headless = Arc::clone(&self.headless);
+        let (kill_sender, kill_receiver) = oneshot::channel::<()>();
+        let (status_sender, status_receiver) = mpsc::channel();
+        let run_control = Arc::clone(&control);
+        std::thread::spawn(move || {
+            let status = pollster::block_on(async {
+                let run = self.run_wasm(wasm_path, location, surface, console);
+                futures::pin_mut!(run);
+                match futures::future::select(run, kill_receiver).await {
+                    Either::Left((status, _)) => status,
+                    // the guest was blocked in a host call and never reached an epoch check
+                    Either::Right(_) => ExitStatus::Killed,
+                }
+            });
+            let status = {
+                let mut state = run_control.state.lock().unwrap();
+                let killed = *state == RunState::Terminating;
+                *state = RunState::Finished;
+                if killed {
+                    ExitStatus::Killed
+                } else {
+                    status
+                }
+            };
+            // dropping the store releases the surface, every GPU resource the guest held and its
+            // slot in the pool, before the status lets the next page start
+            drop(self);
+            log::info!("Wasm finished: {}", status);
+            let _ = status_sender.send(status);
+        });
+
+        WasmInstance {
+            control,
+            engine: instance_engine,
+            kill: Some(kill_sender),
+            status: status_receiver,
+            finished: None,
+            canvas_size,
+            headless,
+        }
     }
 
-    pub async fn run_wasm(&mut self, wasm_path: String, surface: Surface) -> anyhow::Result<()> {
+    async fn run_wasm(
+        &mut self,
+        wasm_path: PathBuf,
+        location: String,
+        surface: Surface,
+        console: ConsolePipes,
+    ) -> ExitStatus {
 
         //self.surface.lock().unwrap().replace(surface);
         match self.surface.lock() {
             Ok(mut guard) => guard.replace(surface),
-            Err(_) => {
-                println!("Failed to lock surface mutex:");
-                return Err(anyhow::anyhow!("Failed to lock surface mutex"));
+            Err(err) => {
+                log::error!("Failed to lock surface mutex: {err}");
+                let e = anyhow::anyhow!("Failed to lock surface mutex: {err}");
+                return ExitStatus::Failed(WasmFailure::new(FailureKind::Load, &e));
             }
         };
         // let wasm_path = format!("./triangle.wasm");
 
-        let component =
-            Component::from_file(&self.engine, &wasm_path).context("Component file not found")?;
+        let prepared = match self.runtime.prepare(&wasm_path) {
+            Ok(prepared) => prepared,
+            Err(status) => return status,
+        };
+        if let Some(required) = prepared.resources_required() {
+            let counts = self.runtime.limits.check_counts(
+                required.num_memories as usize,
+                required.num_tables as usize,
+            );
+            if let Err(limit) = counts {
+                return ExitStatus::LimitExceeded(limit.0);
+            }
+        }
+        let headless = prepared.headless(&self.runtime.engine);
+        self.headless.store(headless, Ordering::Relaxed);
+        if headless {
+            self.budget.whole_run(self.runtime.limits.run_cpu_budget);
+        }
+        if !self.runtime.gpu && prepared.needs_gpu(&self.runtime.engine) {
+            let e = anyhow::anyhow!(
+                "no graphics adapter is available, and the page does not import \
+                 wasi:frame-buffer to draw without one"
+            );
+            return ExitStatus::Failed(WasmFailure::new(FailureKind::NoGpu, &e));
+        }
+
+        // ask the user for whatever the page imports and set up WASI accordingly
+        let origin = origin_of(&location);
+        let capabilities = prepared.capabilities(&self.runtime.engine);
+        let (reply, grants) = oneshot::channel();
+        let request = PermissionRequest {
+            tab: self.store.data().chrome.tab(),
+            origin: origin.clone(),
+            capabilities,
+            reply,
+        };
+        if self.permissions.send(request).is_err() {
+            log::warn!("Permission prompt is gone, denying everything");
+        }
+        // a dropped reply means the prompt was dismissed
+        let grants = grants.await.unwrap_or_default();
+        self.store
+            .data()
+            .frames
+            .set_precise(grants.allows(Capability::Clocks));
+        let mut builder = match wasi_ctx(&origin, &grants, &console) {
+            Ok(builder) => builder,
+            Err(e) => {
+                let e = e.context("failed to set up WASI");
+                return ExitStatus::Failed(WasmFailure::new(FailureKind::Instantiate, &e));
+            }
+        };
 
+        match prepared {
+            Prepared::Component(_, command) => {
+                self.store.data_mut().ctx = builder.build();
+                self.run_component(command).await
+            }
+            Prepared::Module(_, pre) => {
+                self.store.data_mut().p1 = builder.build_p1();
+                self.run_module(pre).await
+            }
+        }
+    }
+
+    async fn run_component(&mut self, command: CommandPre<HostState>) -> ExitStatus {
         // let instance = Example::instantiate_async(&mut self.store, &component, &self.linker)
         //     .await
         //     .unwrap();
 
+        self.start_clock();
+        let instance = match command.instantiate_async(&mut self.store).await {
+            Ok(instance) => instance,
+            Err(e) => {
+                return match limit_exceeded(&e) {
+                    Some(status) => status,
+                    None => ExitStatus::Failed(WasmFailure::new(FailureKind::Instantiate, &e)),
+                }
+            }
+        };
 
-        let instance =
-            wasmtime_wasi::bindings::Command::instantiate_async(&mut self.store, &component, &self.linker)
-                .await
-                .unwrap();
+        match instance.wasi_cli_run().call_run(&mut self.store).await {
+            Ok(Ok(())) => ExitStatus::Exited(0),
+            Ok(Err(())) => ExitStatus::Exited(1),
+            Err(e) => exit_status_from_error(e),
+        }
+    }
 
+    /// Start the CPU budget and the epoch deadline from now, right before the guest first runs.
+    /// Compiling and waiting for the permission prompt do not count against it.
+    fn start_clock(&mut self) {
+        self.budget.restart();
+        self.store.set_epoch_deadline(1);
+    }
 
-        // tokio::spawn(async move {
-        match instance.wasi_cli_run().call_run(&mut self.store).await.context("failed to invoke `run` function") {
-            Ok(Ok(())) => println!("Wasm run completed successfully"),
-            Ok(Err(trap)) => println!("Wasm run trapped: {:?}", trap),
-            Err(e) => println!("Error invoking wasm run: {}", e),
+    /// Run a preview1 command module from its `_start` function.
+    async fn run_module(&mut self, pre: InstancePre<HostState>) -> ExitStatus {
+        self.start_clock();
+        let instance = match pre.instantiate_async(&mut self.store).await {
+            Ok(instance) => instance,
+            Err(e) => {
+                return match limit_exceeded(&e) {
+                    Some(status) => status,
+                    None => ExitStatus::Failed(WasmFailure::new(FailureKind::Instantiate, &e)),
+                }
+            }
+        };
+        let start = match instance.get_typed_func::<(), ()>(&mut self.store, "_start") {
+            Ok(start) => start,
+            Err(e) => {
+                let e = e.context("the module has no `_start` function to run");
+                return ExitStatus::Failed(WasmFailure::new(FailureKind::Instantiate, &e));
+            }
         };
-            //instance.wasi_cli_run().call_run(&mut self.store)
-            //    .await
-            //    .context("failed to invoke `run` function")
-            //    .unwrap()
-            //    .unwrap();
 
-        // });
+        match start.call_async(&mut self.store, ()).await {
+            Ok(()) => ExitStatus::Exited(0),
+            Err(e) => exit_status_from_error(e),
+        }
+    }
+}
+
+fn exit_status_from_error(e: anyhow::Error) -> ExitStatus {
+    if let Some(exit) = e.downcast_ref::<I32Exit>() {
+        return ExitStatus::Exited(exit.0);
+    }
+    if let Some(status) = limit_exceeded(&e) {
+        return status;
+    }
+    ExitStatus::Failed(WasmFailure::new(FailureKind::Trap, &e))
+}
+
+/// The limit `e` stopped the page at, if any. Memory and table counts are checked before the
+/// page is instantiated, see `Prepared::resources_required`.
+fn limit_exceeded(e: &anyhow::Error) -> Option<ExitStatus> {
+    e.downcast_ref::<LimitExceeded>()
+        .map(|limit| ExitStatus::LimitExceeded(limit.0.clone()))
+}
+
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+/// How long `WasmInstance::terminate` waits for the guest thread before giving up on it.
+const TERMINATE_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The guest returned from `run` or called `exit`, with its exit code.
+    Exited(i32),
+    /// The component could not be loaded or instantiated, or the guest trapped.
+    Failed(WasmFailure),
+    /// The guest went over one of its `PageLimits`.
+    LimitExceeded(String),
+    /// The browser terminated the guest.
+    Killed,
+}
+
+impl std::fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitStatus::Exited(0) => write!(f, "exited cleanly"),
+            ExitStatus::Exited(code) => write!(f, "exited with code {}", code),
+            ExitStatus::Failed(failure) => write!(f, "failed: {}", failure),
+            ExitStatus::LimitExceeded(message) => write!(f, "was stopped, {}", message),
+            ExitStatus::Killed => write!(f, "was stopped"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RunState {
+    Running,
+    Paused,
+    Terminating,
+    Finished,
+}
+
+struct Control {
+    state: Mutex<RunState>,
+    changed: Condvar,
+}
+
+/// Handle to a component running on its own thread.
+pub struct WasmInstance {
+    control: Arc<Control>,
+    engine: Engine,
+    kill: Option<oneshot::Sender<()>>,
+    status: mpsc::Receiver<ExitStatus>,
+    finished: Option<ExitStatus>,
+    canvas_size: Arc<Mutex<Option<(Option<u32>, Option<u32>)>>>,
+    headless: Arc<AtomicBool>,
+}
+
+impl WasmInstance {
+    /// Park the guest at its next epoch check until `resume` is called.
+    pub fn pause(&self) {
+        let mut state = self.control.state.lock().unwrap();
+        if *state == RunState::Running {
+            *state = RunState::Paused;
+        }
+    }
 
-        Ok(())
+    pub fn resume(&self) {
+        let mut state = self.control.state.lock().unwrap();
+        if *state == RunState::Paused {
+            *state = RunState::Running;
+            self.control.changed.notify_all();
+        }
     }
 
-    pub async fn stop_wasm(&mut self) -> anyhow::Result<()> {
-        println!("Stopping wasm");
-        // self.store.data_mut().surface_proxy = None;
-        //self.store.data_mut().surface.lock().unwrap().take();
-        Ok(())
+    /// Width and height the guest asked for its first canvas, in logical pixels, once it has
+    /// created it. `None` for either means as large as the page.
+    pub fn requested_canvas_size(&self) -> Option<(Option<u32>, Option<u32>)> {
+        *self.canvas_size.lock().unwrap()
+    }
+
+    /// Whether the guest has no graphics, so its page is the console. Known once it has loaded.
+    pub fn headless(&self) -> bool {
+        self.headless.load(Ordering::Relaxed)
+    }
+
+    /// The guest's exit status, once it has finished on its own.
+    pub fn status(&mut self) -> Option<ExitStatus> {
+        if self.finished.is_none() {
+            self.finished = match self.status.try_recv() {
+                Ok(status) => Some(status),
+                Err(mpsc::TryRecvError::Empty) => None,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    let e = anyhow::anyhow!("wasm thread panicked");
+                    Some(ExitStatus::Failed(WasmFailure::new(FailureKind::Trap, &e)))
+                }
+            };
+        }
+        self.finished.clone()
+    }
+
+    /// Stop the guest and wait for it to release its surface.
+    ///
+    /// A guest executing wasm is interrupted at the next epoch tick and one blocked in a host
+    /// call is dropped at its await point. If the thread still has not finished after a short
+    /// timeout it is left to finish in the background and the guest is reported as killed.
+    pub fn terminate(mut self) -> ExitStatus {
+        if let Some(status) = self.status() {
+            return status;
+        }
+        {
+            let mut state = self.control.state.lock().unwrap();
+            *state = RunState::Terminating;
+            self.control.changed.notify_all();
+        }
+        if let Some(kill) = self.kill.take() {
+            let _ = kill.send(());
+        }
+        self.engine.increment_epoch();
+
+        match self.status.recv_timeout(TERMINATE_TIMEOUT) {
+            Ok(status) => status,
+            Err(_) => {
+                log::warn!("Wasm thread did not stop in time, detaching it");
+                ExitStatus::Killed
+            }
+        }
     }
 }
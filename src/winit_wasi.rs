@@ -4,7 +4,7 @@ use std::{
     fmt::Debug,
     sync::{Arc, Mutex},
     thread::{self, sleep},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use wasi_surface_wasmtime::{Surface, SurfaceDesc, SurfaceProxy};
@@ -14,38 +14,230 @@ use raw_window_handle::{
 use wasi_graphics_context_wasmtime::DisplayApi;
 use winit::{
     application::ApplicationHandler,
-    dpi::{PhysicalSize, Size},
-    event::{ElementState, WindowEvent},
-    event_loop::{ActiveEventLoop, EventLoop, EventLoopProxy},
+    dpi::{PhysicalPosition, PhysicalSize, Size},
+    event::{ElementState, Ime, MouseScrollDelta, TouchPhase, WindowEvent},
+    event_loop::{ActiveEventLoop, EventLoop, EventLoopClosed, EventLoopProxy},
     keyboard::ModifiersState,
     window::{Window, WindowAttributes, WindowId},
 };
 
+use crate::frames::FrameClock;
+use crate::host::input;
+use crate::input::InputQueue;
+use crate::text_input::CanvasRect;
+
+/// Which window an event arrived at, and so which coordinate space its positions are in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventSpace {
+    /// The page's own window, positions are relative to the page area.
+    Page,
+    /// The browser's main window, positions are relative to the whole window.
+    Parent,
+}
+
 pub struct WinitEventToSurfaceProxy {
     pointer_pos: (f64, f64),
+    pointer_inside: bool,
+    focused: bool,
     modifiers: ModifiersState,
+    scale_factor: f64,
+    // where the page sits in the main window and how big it is on screen, in physical pixels
+    area_origin: PhysicalPosition<f64>,
+    area_size: PhysicalSize<u32>,
+    // size of the canvas the guest draws to, in physical pixels
+    canvas_size: PhysicalSize<u32>,
     proxy: SurfaceProxy,
+    input: InputQueue,
+    frames: FrameClock,
 }
 
 impl WinitEventToSurfaceProxy {
-    pub fn new(proxy: SurfaceProxy) -> Self {
+    pub fn new(proxy: SurfaceProxy, input: InputQueue, frames: FrameClock) -> Self {
         Self {
             pointer_pos: (0.0, 0.0),
+            pointer_inside: false,
+            focused: false,
             modifiers: ModifiersState::default(),
+            scale_factor: 0.0,
+            area_origin: PhysicalPosition::new(0.0, 0.0),
+            area_size: PhysicalSize::new(0, 0),
+            canvas_size: PhysicalSize::new(0, 0),
             proxy,
+            input,
+            frames,
+        }
+    }
+
+    /// Tell the proxy where the page is drawn, in physical pixels of the main window.
+    ///
+    /// egui lays the panel out in points, so callers scale by the window's pixels per point
+    /// first; winit already reports cursor positions in physical pixels.
+    pub fn set_area(&mut self, origin: PhysicalPosition<i32>, size: PhysicalSize<u32>) {
+        self.area_origin = PhysicalPosition::new(origin.x as f64, origin.y as f64);
+        self.area_size = size;
+        if self.canvas_size.width == 0 || self.canvas_size.height == 0 {
+            self.canvas_size = size;
+        }
+    }
+
+    /// Whether the last pointer position seen was over the page.
+    pub fn pointer_inside(&self) -> bool {
+        self.pointer_inside
+    }
+
+    /// Start the page's next animation frame, `presented` being when the main window's frame
+    /// went out.
+    pub fn animation_frame(&mut self, presented: Instant) {
+        self.frames.tick(presented);
+        self.proxy.animation_frame();
+    }
+
+    /// Send a frame to a further canvas of a page whose frame was already started by the
+    /// proxy of its first canvas.
+    pub fn canvas_frame(&self) {
+        self.proxy.animation_frame();
+    }
+
+    /// The page's input queue, shared by the proxies of all its canvases.
+    pub fn input(&self) -> InputQueue {
+        self.input.clone()
+    }
+
+    pub fn frames(&self) -> FrameClock {
+        self.frames.clone()
+    }
+
+    /// Tell the page whether it has keyboard focus, if that changed.
+    pub fn set_focused(&mut self, focused: bool) {
+        if self.focused != focused {
+            self.focused = focused;
+            self.input.activation().set_focused(focused);
+            self.input.push(input::Event::Focus(focused));
+        }
+    }
+
+    /// Tell the page the display's scale factor, if that changed.
+    pub fn set_scale_factor(&mut self, scale_factor: f64) {
+        if self.scale_factor != scale_factor {
+            self.scale_factor = scale_factor;
+            self.input.push(input::Event::ScaleFactor(scale_factor));
+        }
+    }
+
+    /// Raw mouse movement from `DeviceEvent::MouseMotion`, passed on while the pointer is
+    /// locked.
+    pub fn pointer_motion(&mut self, (delta_x, delta_y): (f64, f64)) {
+        if self.input.view().pointer_locked() {
+            self.input
+                .push(input::Event::PointerMotion(input::MotionEvent { delta_x, delta_y }));
+        }
+    }
+
+    fn set_pointer_inside(&mut self, inside: bool) {
+        if self.pointer_inside != inside {
+            self.pointer_inside = inside;
+            self.input.push(if inside {
+                input::Event::PointerEnter
+            } else {
+                input::Event::PointerLeave
+            });
+        }
+    }
+
+    /// Map a pointer position onto the guest's canvas.
+    ///
+    /// Canvas coordinates are physical pixels of the canvas, the same unit the guest gets for
+    /// its width and height. When the canvas is not the same size as the area it is shown in,
+    /// positions are scaled to match.
+    fn to_canvas(&self, position: PhysicalPosition<f64>, space: EventSpace) -> (f64, f64) {
+        let (x, y) = match space {
+            EventSpace::Page => (position.x, position.y),
+            EventSpace::Parent => (
+                position.x - self.area_origin.x,
+                position.y - self.area_origin.y,
+            ),
+        };
+        if self.area_size.width == 0 || self.area_size.height == 0 {
+            return (x, y);
+        }
+        (
+            x * self.canvas_size.width as f64 / self.area_size.width as f64,
+            y * self.canvas_size.height as f64 / self.area_size.height as f64,
+        )
+    }
+
+    fn inside_canvas(&self, (x, y): (f64, f64)) -> bool {
+        x >= 0.0
+            && y >= 0.0
+            && x < self.canvas_size.width as f64
+            && y < self.canvas_size.height as f64
+    }
+
+    /// Where `rect` on the guest's canvas is in the window of `space`, the reverse of
+    /// `to_canvas`.
+    pub fn from_canvas(
+        &self,
+        rect: CanvasRect,
+        space: EventSpace,
+    ) -> (PhysicalPosition<f64>, PhysicalSize<f64>) {
+        let (scale_x, scale_y) =
+            if self.canvas_size.width == 0 || self.canvas_size.height == 0 {
+                (1.0, 1.0)
+            } else {
+                (
+                    self.area_size.width as f64 / self.canvas_size.width as f64,
+                    self.area_size.height as f64 / self.canvas_size.height as f64,
+                )
+            };
+        let (origin_x, origin_y) = match space {
+            EventSpace::Page => (0.0, 0.0),
+            EventSpace::Parent => (self.area_origin.x, self.area_origin.y),
+        };
+        (
+            PhysicalPosition::new(origin_x + rect.x * scale_x, origin_y + rect.y * scale_y),
+            PhysicalSize::new(rect.width * scale_x, rect.height * scale_y),
+        )
+    }
+
+    /// Hand the text of a key press to a page with a focused text field. Text an input method
+    /// composes arrives as `WindowEvent::Ime` instead, and shortcuts such as ctrl+c insert
+    /// nothing.
+    fn commit_key_text(&self, input: &winit::event::KeyEvent) {
+        let text_input = self.input.text_input();
+        if !text_input.enabled() || self.modifiers.control_key() || self.modifiers.super_key() {
+            return;
+        }
+        if let Some(text) = typed_text(input) {
+            text_input.commit(text);
         }
     }
 
-    pub fn send_event(&mut self, event: &WindowEvent) {
+    pub fn send_event(&mut self, event: &WindowEvent, space: EventSpace) {
         match event {
             WindowEvent::CursorMoved { position, .. } => {
-                self.pointer_pos = (position.x, position.y);
-                self.proxy.pointer_move(wasi_surface_wasmtime::PointerEvent {
-                    x: position.x,
-                    y: position.y,
-                });
+                // a locked pointer stays where it was, movement comes from `pointer_motion`
+                if self.input.view().pointer_locked() {
+                    return;
+                }
+                self.pointer_pos = self.to_canvas(*position, space);
+                let inside = space == EventSpace::Page || self.inside_canvas(self.pointer_pos);
+                self.set_pointer_inside(inside);
+                if !self.pointer_inside {
+                    return;
+                }
+                let (x, y) = self.pointer_pos;
+                self.proxy.pointer_move(wasi_surface_wasmtime::PointerEvent { x, y });
 
             }
+            WindowEvent::CursorEntered { .. } => {
+                // the position follows with the next CursorMoved
+                if space == EventSpace::Page {
+                    self.set_pointer_inside(true);
+                }
+            }
+            WindowEvent::CursorLeft { .. } => {
+                self.set_pointer_inside(false);
+            }
             WindowEvent::ModifiersChanged(modifiers) => {
                 self.modifiers = modifiers.state();
             }
@@ -55,11 +247,11 @@ impl WinitEventToSurfaceProxy {
                         winit::keyboard::PhysicalKey::Code(code) => code.try_into().ok(),
                         winit::keyboard::PhysicalKey::Unidentified(_) => None,
                     },
-                    text: match &input.logical_key {
-                        winit::keyboard::Key::Character(char) => Some(char.to_string()),
-                        winit::keyboard::Key::Named(_)
-                        | winit::keyboard::Key::Unidentified(_)
-                        | winit::keyboard::Key::Dead(_) => None,
+                    // with a text field focused the text arrives once, as a commit
+                    text: if self.input.text_input().enabled() {
+                        None
+                    } else {
+                        typed_text(input)
                     },
                     alt_key: self.modifiers.alt_key(),
                     ctrl_key: self.modifiers.control_key(),
@@ -68,6 +260,8 @@ impl WinitEventToSurfaceProxy {
                 };
                 match input.state {
                     ElementState::Pressed => {
+                        self.input.activation().activate();
+                        self.commit_key_text(input);
                         self.proxy.key_down(event);
                     }
                     ElementState::Released => {
@@ -75,22 +269,89 @@ impl WinitEventToSurfaceProxy {
                     }
                 }
             }
-            WindowEvent::MouseInput { state, .. } => {
+            WindowEvent::MouseInput { state, button, .. } => {
+                if !self.pointer_inside {
+                    return;
+                }
                 let (pointer_x, pointer_y) = self.pointer_pos;
                 let event = wasi_surface_wasmtime::PointerEvent {
                     x: pointer_x,
                     y: pointer_y,
                 };
+                let extended = input::PointerEvent {
+                    x: pointer_x,
+                    y: pointer_y,
+                    button: crate::input::pointer_button(*button),
+                    modifiers: crate::input::modifiers(self.modifiers),
+                };
                 match state {
                     ElementState::Pressed => {
+                        self.input.activation().activate();
                         self.proxy.pointer_down(event);
+                        self.input.push(input::Event::PointerDown(extended));
                     }
                     ElementState::Released => {
                         self.proxy.pointer_up(event);
+                        self.input.push(input::Event::PointerUp(extended));
                     }
                 }
             }
+            WindowEvent::MouseWheel { delta, .. } => {
+                if !self.pointer_inside {
+                    return;
+                }
+                let (x, y) = self.pointer_pos;
+                let (delta_x, delta_y, delta_mode) = match delta {
+                    MouseScrollDelta::LineDelta(x, y) => {
+                        (*x as f64, *y as f64, input::DeltaMode::Line)
+                    }
+                    MouseScrollDelta::PixelDelta(delta) => {
+                        (delta.x, delta.y, input::DeltaMode::Pixel)
+                    }
+                };
+                self.input.push(input::Event::Wheel(input::WheelEvent {
+                    x,
+                    y,
+                    delta_x,
+                    delta_y,
+                    delta_mode,
+                    modifiers: crate::input::modifiers(self.modifiers),
+                }));
+            }
+            WindowEvent::Touch(touch) => {
+                let (x, y) = self.to_canvas(touch.location, space);
+                // a touch that starts outside the page belongs to the browser
+                if space == EventSpace::Parent && !self.inside_canvas((x, y)) {
+                    return;
+                }
+                if touch.phase == TouchPhase::Started {
+                    self.input.activation().activate();
+                }
+                self.input.push(input::Event::Touch(input::TouchEvent {
+                    id: touch.id,
+                    phase: crate::input::touch_phase(touch.phase),
+                    x,
+                    y,
+                    force: touch.force.map(|force| force.normalized()),
+                }));
+            }
+            WindowEvent::Ime(ime) => {
+                let text_input = self.input.text_input();
+                match ime {
+                    Ime::Preedit(text, cursor) => text_input.preedit(text.clone(), *cursor),
+                    Ime::Commit(text) => text_input.commit(text.clone()),
+                    Ime::Enabled | Ime::Disabled => {}
+                }
+            }
+            WindowEvent::ScaleFactorChanged { scale_factor, .. } => {
+                self.set_scale_factor(*scale_factor);
+            }
             WindowEvent::Resized(new_size) => {
+                // the main window's size has nothing to do with the canvas
+                if space == EventSpace::Parent {
+                    return;
+                }
+                self.canvas_size = *new_size;
                 self.proxy.canvas_resize(wasi_surface_wasmtime::ResizeEvent {
                     height: new_size.height,
                     width: new_size.width,
@@ -145,6 +406,43 @@ impl DisplayApi for MyWindowWrapper {
     }
 }
 
+/// The text a key event types, if any. Named keys such as Enter and Tab carry control
+/// characters, and macOS reports arrow and function keys as private use characters.
+fn typed_text(input: &winit::event::KeyEvent) -> Option<String> {
+    // winit's text already has dead keys and compose sequences applied
+    let text = match (&input.text, &input.logical_key) {
+        (Some(text), _) => text.to_string(),
+        (None, winit::keyboard::Key::Character(char)) => char.to_string(),
+        _ => return None,
+    };
+    let printable = |c: char| !c.is_control() && !('\u{e000}'..='\u{f8ff}').contains(&c);
+    (!text.is_empty() && text.chars().all(printable)).then_some(text)
+}
+
+/// A surface with no window behind it, handed out when a canvas window could not be opened.
+/// The guest traps before it can draw to it, see `HostState::canvas_error`.
+pub struct NoWindow;
+impl HasDisplayHandle for NoWindow {
+    fn display_handle(&self) -> Result<DisplayHandle<'_>, HandleError> {
+        Err(HandleError::Unavailable)
+    }
+}
+impl HasWindowHandle for NoWindow {
+    fn window_handle(&self) -> Result<WindowHandle<'_>, HandleError> {
+        Err(HandleError::Unavailable)
+    }
+}
+impl DisplayApi for NoWindow {
+    fn height(&self) -> u32 {
+        0
+    }
+
+    fn width(&self) -> u32 {
+        0
+    }
+
+    fn request_set_size(&self, _width: Option<u32>, _height: Option<u32>) {}
+}
 
 pub struct WasiWinitEventLoop {
     event_loop: EventLoop<MainThreadAction>,
@@ -219,7 +517,7 @@ impl WasiWinitEventLoop {
 
             fn user_event(&mut self, event_loop: &ActiveEventLoop, event: MainThreadAction) {
                 match event {
-                    MainThreadAction::CreateWindow(desc, response_channel) => {
+                    MainThreadAction::CreateWindow(_, desc, response_channel) => {
                         let mut window_options = WindowAttributes::default();
                         if let (Some(width), Some(height)) = (desc.width, desc.height) {
                             window_options = window_options.with_inner_size(Size::Logical(
@@ -242,11 +540,12 @@ impl WasiWinitEventLoop {
                             .unwrap()
                             .insert(window_id, canvas.proxy());
 
-                        response_channel.send(canvas).unwrap();
+                        response_channel.send(Some(canvas)).unwrap();
                     }
                     MainThreadAction::Spawn(f, res) => {
                         res.send(f()).unwrap();
                     }
+                    MainThreadAction::ReadClipboard(_) | MainThreadAction::WriteClipboard(_) => {}
                 }
             }
 
@@ -343,12 +642,36 @@ pub struct WasiWinitEventLoopProxy {
 }
 
 impl WasiWinitEventLoopProxy {
-    pub async fn create_window(&self, desc: SurfaceDesc) -> Surface {
+    /// Wrap the proxy of an event loop whose handler carries out `MainThreadAction`s.
+    pub fn new(proxy: EventLoopProxy<MainThreadAction>) -> Self {
+        Self { proxy }
+    }
+
+    /// Open a window for another canvas of the page in `tab`. `None` if the page is gone, the
+    /// window could not be created or the browser is shutting down.
+    pub async fn create_window(&self, tab: i32, desc: SurfaceDesc) -> Option<Surface> {
+        let (sender, receiver) = oneshot::channel();
+        self.proxy
+            .send_event(MainThreadAction::CreateWindow(tab, desc, sender))
+            .ok()?;
+        receiver.await.ok().flatten()
+    }
+
+    /// Read the clipboard on the main thread, blocking until it answers. `None` if it holds no
+    /// text or the browser is shutting down.
+    pub fn read_clipboard(&self) -> Option<String> {
         let (sender, receiver) = oneshot::channel();
         self.proxy
-            .send_event(MainThreadAction::CreateWindow(desc, sender))
-            .unwrap();
-        receiver.await.unwrap()
+            .send_event(MainThreadAction::ReadClipboard(sender))
+            .ok()?;
+        receiver.recv().ok().flatten()
+    }
+
+    /// Put `text` on the clipboard. Returns `false` if the browser is shutting down.
+    pub fn write_clipboard(&self, text: String) -> bool {
+        self.proxy
+            .send_event(MainThreadAction::WriteClipboard(text))
+            .is_ok()
     }
 
     pub async fn spawn<F, T>(&self, f: F) -> T
@@ -361,15 +684,25 @@ impl WasiWinitEventLoopProxy {
             Box::new(res) as Box<dyn Any + Send + Sync>
         });
         let (sender, receiver) = oneshot::channel();
-        self.proxy
-            .send_event(MainThreadAction::Spawn(boxed, sender))
-            .unwrap();
-        *receiver.await.unwrap().downcast().unwrap()
+        let res = match self.proxy.send_event(MainThreadAction::Spawn(boxed, sender)) {
+            Ok(()) => receiver
+                .await
+                .expect("main thread dropped a spawned closure"),
+            // the browser is shutting down, nothing is left on the main thread to race with
+            Err(EventLoopClosed(MainThreadAction::Spawn(f, _))) => f(),
+            Err(EventLoopClosed(_)) => unreachable!(),
+        };
+        *res.downcast().unwrap()
     }
 }
 
-enum MainThreadAction {
-    CreateWindow(SurfaceDesc, oneshot::Sender<Surface>),
+/// Work a page's runtime thread hands to the main thread, which owns all windows.
+pub enum MainThreadAction {
+    /// A canvas window for the page in the tab, always answered, with `None` on failure.
+    CreateWindow(i32, SurfaceDesc, oneshot::Sender<Option<Surface>>),
+    /// Text on the system clipboard, through egui.
+    ReadClipboard(oneshot::Sender<Option<String>>),
+    WriteClipboard(String),
     Spawn(
         Box<dyn FnOnce() -> Box<dyn Any + Send + Sync> + Send + Sync>,
         oneshot::Sender<Box<dyn Any + Send + Sync>>,
@@ -379,11 +712,14 @@ enum MainThreadAction {
 impl Debug for MainThreadAction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::CreateWindow(arg0, arg1) => f
+            Self::CreateWindow(arg0, arg1, arg2) => f
                 .debug_tuple("CreateWindow")
                 .field(arg0)
                 .field(arg1)
+                .field(arg2)
                 .finish(),
+            Self::ReadClipboard(_) => f.debug_tuple("ReadClipboard").finish(),
+            Self::WriteClipboard(_) => f.debug_tuple("WriteClipboard").finish(),
             Self::Spawn(_, _) => f.debug_tuple("Spawn").finish(),
         }
     }